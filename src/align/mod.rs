@@ -6,10 +6,10 @@ use std::collections::HashMap;
 
 use petgraph::prelude::*;
 
-use super::*;
 use crate::align::poa::*;
+use crate::utils::PoaElt;
 
-mod poa;
+pub mod poa;
 
 const SIMDW: usize = 8;
 const NEG_INF: i32 = -3_000_000;
@@ -119,6 +119,7 @@ fn add_alignment(
     Some(first_id.unwrap())
 }
 
+#[allow(clippy::too_many_arguments)]
 fn build_matrix(
     g: &POAGraph,
     seq: &Sequence,
@@ -479,6 +480,8 @@ fn affine_sw(g: &POAGraph, seq: &Sequence, settings: &AffineNWSettings) -> (i32,
     (max_score, (graph_idxs, seq_idxs))
 }
 
+/// Build the partial order alignment of `seqs`, returning the POA graph and
+/// the node each sequence starts from
 pub fn align(seqs: &Sequences) -> (POAGraph, HashMap<SeqID, NodeIndex>) {
     let settings = AffineNWSettings {
         matches: 10,
@@ -492,7 +495,7 @@ pub fn align(seqs: &Sequences) -> (POAGraph, HashMap<SeqID, NodeIndex>) {
     //    small sequences insertion;
     // 2. Saving memory is always welcome.
     let mut sorted_seqs = seqs.iter().collect::<Vec<_>>();
-    sorted_seqs.sort_by(|(_, seq1), (_, seq2)| seq2.len().cmp(&seq1.len()));
+    sorted_seqs.sort_by_key(|(_, seq)| std::cmp::Reverse(seq.len()));
 
     let starts = sorted_seqs
         .iter()
@@ -516,6 +519,8 @@ pub fn align(seqs: &Sequences) -> (POAGraph, HashMap<SeqID, NodeIndex>) {
     (g, starts)
 }
 
+/// Unroll the partial order alignment `g` into one aligned sequence per
+/// sequence ID
 pub fn poa_to_strings(g: &POAGraph, starts: &Heads) -> HashMap<usize, Vec<PoaElt>> {
     let nodes = petgraph::algo::toposort(g, None).ok().unwrap();
     let rank_to_column = nodes
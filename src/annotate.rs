@@ -0,0 +1,173 @@
+use crate::utils::*;
+use newick::*;
+use std::collections::HashSet;
+use syntesuite::genebook::FamilyID;
+
+// NHX values can not contain any of these characters
+fn nhx_escape(s: &str) -> String {
+    s.chars()
+        .map(|c| match c {
+            ':' | ',' | ';' | '(' | ')' | '[' | ']' | '=' => '_',
+            c if c.is_whitespace() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+/// Mean pairwise Jaccard index between the landscapes of `xs` and the ones of
+/// `ys`, skipping self-comparisons.
+fn conservation(xs: &[HashSet<FamilyID>], ys: &[HashSet<FamilyID>], same: bool) -> Option<f32> {
+    let mut total = 0.;
+    let mut count = 0;
+    for (i, x) in xs.iter().enumerate() {
+        for (j, y) in ys.iter().enumerate() {
+            if same && i >= j {
+                continue;
+            }
+            if !x.is_empty() || !y.is_empty() {
+                total += jaccard(x, y);
+                count += 1;
+            }
+        }
+    }
+    (count > 0).then(|| total / count as f32)
+}
+
+/// Store the syntenic information computed by genominicus as NHX attributes in
+/// `tree`:
+///   - `S`, `CHR`, `POS`, `STRAND`: the location of a leaf gene (`S` is only
+///     set if not already present);
+///   - `FLIPPED`: `Y` if the landscape of the leaf has been reversed to match
+///     the orientation of its relatives, `N` otherwise;
+///   - `LSC`: for an inner node, the mean pairwise Jaccard index between the
+///     landscapes of its leaves; for a leaf, the mean Jaccard index between its
+///     landscape and the ones of its sister clade.
+pub fn annotate(tree: &mut NewickTree, genes: &GeneCache, flipped: &HashSet<String>) {
+    let nodes = tree.nodes().collect::<Vec<_>>();
+    for n in nodes {
+        if tree[n].is_leaf() {
+            let Some(name) = tree.name(n).cloned() else {
+                continue;
+            };
+            let sisters = tree
+                .siblings(n)
+                .into_iter()
+                .flat_map(|s| tree.leaves_of(s))
+                .filter_map(|l| landscape_set(genes, tree, l))
+                .collect::<Vec<_>>();
+            let lsc =
+                landscape_set(genes, tree, n).and_then(|me| conservation(&[me], &sisters, false));
+
+            if let Some(gene) = genes.get(&name) {
+                let attrs = tree.attrs_mut(n);
+                attrs
+                    .entry("S".to_string())
+                    .or_insert_with(|| nhx_escape(&gene.species));
+                attrs.insert("CHR".into(), nhx_escape(&gene.chr));
+                attrs.insert("POS".into(), gene.pos.to_string());
+                attrs.insert("STRAND".into(), char::from(gene.strand).to_string());
+                attrs.insert(
+                    "FLIPPED".into(),
+                    if flipped.contains(&name) { "Y" } else { "N" }.into(),
+                );
+                if let Some(lsc) = lsc {
+                    attrs.insert("LSC".into(), format!("{:.3}", lsc));
+                }
+            }
+        } else {
+            let landscapes = tree
+                .leaves_of(n)
+                .into_iter()
+                .filter_map(|l| landscape_set(genes, tree, l))
+                .collect::<Vec<_>>();
+            if let Some(lsc) = conservation(&landscapes, &landscapes, true) {
+                tree.attrs_mut(n)
+                    .insert("LSC".into(), format!("{:.3}", lsc));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use syntesuite::genebook::{Gene, TailGene};
+    use syntesuite::Strand;
+
+    fn gene(species: &str, chr: &str, left: &[FamilyID], right: &[FamilyID]) -> Gene {
+        let tail = |families: &[FamilyID]| {
+            families
+                .iter()
+                .map(|&family| TailGene {
+                    family,
+                    strand: Strand::Direct,
+                })
+                .collect()
+        };
+        Gene {
+            id: String::new(),
+            species: species.to_owned(),
+            family: 0,
+            chr: chr.to_owned(),
+            pos: 12,
+            strand: Strand::Direct,
+            left_landscape: tail(left),
+            right_landscape: tail(right),
+        }
+    }
+
+    fn set(families: &[FamilyID]) -> HashSet<FamilyID> {
+        families.iter().copied().collect()
+    }
+
+    #[test]
+    fn escape() {
+        assert_eq!(nhx_escape("chr1"), "chr1");
+        assert_eq!(nhx_escape("a[b]c:d;e,f=g h"), "a_b_c_d_e_f_g_h");
+        assert_eq!(nhx_escape("(x)"), "_x_");
+    }
+
+    #[test]
+    fn conservation_scores() {
+        // The second landscape is the first one read in the opposite
+        // orientation, the third one shares no family with them
+        let landscapes = [set(&[1, 2, 3]), set(&[3, 2, 1]), set(&[4, 5])];
+        let [a, flipped, disjoint] = [&landscapes[0..1], &landscapes[1..2], &landscapes[2..3]];
+        assert_eq!(conservation(a, flipped, false), Some(1.));
+        assert_eq!(conservation(a, disjoint, false), Some(0.));
+        assert_eq!(conservation(a, &[], false), None);
+        // Within a clade, each pair is only compared once, and never to itself
+        assert_eq!(conservation(&landscapes, &landscapes, true), Some(1. / 3.));
+        assert_eq!(conservation(a, a, true), None);
+    }
+
+    #[test]
+    fn attributes() {
+        let mut t = newick::one_from_string("((a,b[&&NHX:S=custom])ab,c)root;").unwrap();
+        let genes = GeneCache::from([
+            ("a".to_owned(), gene("hsapiens", "chr:1", &[1, 2], &[3])),
+            ("b".to_owned(), gene("mmusculus", "2", &[3], &[2, 1])),
+            ("c".to_owned(), gene("drerio", "3", &[7], &[8])),
+        ]);
+        annotate(&mut t, &genes, &HashSet::from(["b".to_owned()]));
+        let attrs = |name: &str| {
+            let n = t.find_node(|n| n.name.as_deref() == Some(name)).unwrap();
+            t.attrs(n).clone()
+        };
+
+        let a = attrs("a");
+        assert_eq!(a["S"], "hsapiens");
+        assert_eq!(a["CHR"], "chr_1");
+        assert_eq!(a["POS"], "12");
+        assert_eq!(a["STRAND"], "+");
+        assert_eq!(a["FLIPPED"], "N");
+        assert_eq!(a["LSC"], "1.000");
+
+        let b = attrs("b");
+        assert_eq!(b["S"], "custom");
+        assert_eq!(b["FLIPPED"], "Y");
+        assert_eq!(attrs("c")["LSC"], "0.000");
+        assert_eq!(attrs("ab")["LSC"], "1.000");
+        assert_eq!(attrs("root")["LSC"], "0.333");
+    }
+}
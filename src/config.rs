@@ -0,0 +1,307 @@
+//! Project-wide defaults of the command line options, read from a
+//! `genominicus.toml` file such as:
+//!
+//! ```toml
+//! window = 10
+//!
+//! [plot]
+//! database = "genomes.db"
+//! annotations = ["links", "dids"]
+//!
+//! [dimensions]
+//! font-size = 12
+//!
+//! [preset.paper]
+//! plot.type = "barcode"
+//! dimensions.gene-width = 20
+//! ```
+//!
+//! The top-level keys set the global options, and each table named after a
+//! subcommand sets the options of this subcommand, by their long name. The
+//! `dimensions` table sets the sizes of the elements of the SVG plots. Each
+//! preset is a table of the same shape, overriding the rest of the file when
+//! selected. Relative paths are resolved from the directory of the file.
+use anyhow::*;
+use clap::builder::Resettable;
+use clap::{Command, ValueHint};
+use std::path::{Path, PathBuf};
+use toml::{Table, Value};
+
+use crate::utils::Dimensions;
+
+pub const CONFIG_FILE: &str = "genominicus.toml";
+const DIMENSIONS: &str = "dimensions";
+const PRESETS: &str = "preset";
+
+#[derive(Debug, Default)]
+pub struct Config {
+    /// The defaults of the command line options
+    options: Table,
+    /// The directory of the configuration file
+    root: PathBuf,
+    pub dimensions: Dimensions,
+}
+impl Config {
+    /// Look for a configuration file in `dir` and its ancestors
+    pub fn find(dir: &Path) -> Option<PathBuf> {
+        dir.ancestors()
+            .map(|d| d.join(CONFIG_FILE))
+            .find(|f| f.is_file())
+    }
+
+    /// Read the configuration from `filename`, with `preset` applied if any
+    pub fn from_file(filename: &Path, preset: Option<&str>) -> Result<Self> {
+        let root = filename.parent().map(Path::to_path_buf).unwrap_or_default();
+        let filename = filename.display();
+        let mut table = std::fs::read_to_string(filename.to_string())
+            .with_context(|| anyhow!("failed to read {}", filename))?
+            .parse::<Table>()
+            .with_context(|| anyhow!("failed to parse {}", filename))?;
+
+        let presets = match table.remove(PRESETS) {
+            None => Table::new(),
+            Some(Value::Table(presets)) => presets,
+            Some(_) => bail!("{}: `{}` should be a table of presets", filename, PRESETS),
+        };
+        if let Some(preset) = preset {
+            match presets.get(preset) {
+                Some(Value::Table(overrides)) => merge(&mut table, overrides.clone()),
+                Some(_) => bail!("{}: preset `{}` should be a table", filename, preset),
+                None => bail!(
+                    "preset `{}` not found in {}; available presets: {}",
+                    preset,
+                    filename,
+                    presets.keys().cloned().collect::<Vec<_>>().join(", ")
+                ),
+            }
+        }
+
+        let dimensions = table
+            .remove(DIMENSIONS)
+            .map(Value::try_into)
+            .transpose()
+            .with_context(|| anyhow!("{}: invalid `{}`", filename, DIMENSIONS))?
+            .unwrap_or_default();
+
+        Ok(Config {
+            options: table,
+            root,
+            dimensions,
+        })
+    }
+
+    /// Whether the configuration sets the option `id` of `subcommand`
+    pub fn sets(&self, subcommand: &str, id: &str) -> bool {
+        self.options
+            .get(subcommand)
+            .and_then(Value::as_table)
+            .is_some_and(|options| options.keys().any(|k| k.replace('-', "_") == id))
+    }
+
+    /// Set the options of the configuration as the defaults of `cmd`
+    pub fn apply(&self, cmd: Command) -> Result<Command> {
+        set_defaults(cmd, &self.options, &self.root)
+    }
+}
+
+/// Recursively override the values of `base` with the ones of `overrides`
+fn merge(base: &mut Table, overrides: Table) {
+    for (key, value) in overrides {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(base)), Value::Table(overrides)) => merge(base, overrides),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+fn to_arg_value(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.to_owned()),
+        Value::Integer(_) | Value::Float(_) | Value::Boolean(_) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+fn set_defaults(mut cmd: Command, options: &Table, root: &Path) -> Result<Command> {
+    for (key, value) in options {
+        if let (Value::Table(options), Some(_)) = (value, cmd.find_subcommand(key)) {
+            let mut error = None;
+            cmd = cmd.mut_subcommand(key, |sub| match set_defaults(sub.clone(), options, root) {
+                Result::Ok(sub) => sub,
+                Err(e) => {
+                    error = Some(e.context(format!("in [{}]", key)));
+                    sub
+                }
+            });
+            if let Some(error) = error {
+                return Err(error);
+            }
+            continue;
+        }
+
+        // Options without a long name are designated by their ID, in kebab-case
+        let arg = cmd
+            .get_arguments()
+            .find(|a| {
+                !a.is_positional()
+                    && (a.get_long() == Some(key) || a.get_id() == key.replace('-', "_").as_str())
+            })
+            .ok_or_else(|| anyhow!("unknown option `{}`", key))?;
+        let id = arg.get_id().clone();
+        let is_path = matches!(
+            arg.get_value_hint(),
+            ValueHint::AnyPath | ValueHint::FilePath | ValueHint::DirPath
+        );
+        let values = match value {
+            Value::Array(values) => values.iter().map(to_arg_value).collect(),
+            value => to_arg_value(value).map(|v| vec![v]),
+        }
+        .ok_or_else(|| anyhow!("invalid value for `{}`: {}", key, value))?
+        .into_iter()
+        .map(|v| {
+            if is_path {
+                root.join(v).to_string_lossy().into_owned()
+            } else {
+                v
+            }
+        })
+        .collect::<Vec<_>>();
+        cmd = cmd.mut_arg(id, |a| {
+            a.default_values(values)
+                .required(false)
+                .required_unless_present(Resettable::Reset)
+        });
+    }
+    Ok(cmd)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Arg;
+
+    const CONFIG: &str = r#"
+window = 10
+
+[plot]
+database = "genomes.db"
+annotations = ["links", "dids"]
+
+[dimensions]
+font-size = 12
+
+[preset.paper]
+window = 5
+plot.type = "barcode"
+"#;
+
+    fn command() -> Command {
+        Command::new("genominicus")
+            .arg(Arg::new("window").long("window").default_value("15"))
+            .subcommand(
+                Command::new("plot")
+                    .arg(
+                        Arg::new("database")
+                            .short('D')
+                            .long("database")
+                            .required(true)
+                            .value_hint(ValueHint::FilePath),
+                    )
+                    .arg(Arg::new("annotations").long("annotations").num_args(1..))
+                    .arg(Arg::new("graph_type").short('T').long("type")),
+            )
+    }
+
+    fn from_str(content: &str, preset: Option<&str>) -> Result<Config> {
+        let dir = std::env::temp_dir().join(format!("genominicus-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let filename = dir.join(format!("{}-{}", preset.unwrap_or("none"), CONFIG_FILE));
+        std::fs::write(&filename, content).unwrap();
+        let config = Config::from_file(&filename, preset);
+        std::fs::remove_file(&filename).unwrap();
+        config
+    }
+
+    #[test]
+    fn merge_tables() {
+        let mut base = CONFIG.parse::<Table>().unwrap();
+        let overrides = "window = 5\nplot.type = \"barcode\"\n"
+            .parse::<Table>()
+            .unwrap();
+        merge(&mut base, overrides);
+        assert_eq!(base["window"].as_integer(), Some(5));
+        assert_eq!(base["plot"]["type"].as_str(), Some("barcode"));
+        // Sibling keys of the overridden ones are kept
+        assert_eq!(base["plot"]["database"].as_str(), Some("genomes.db"));
+        assert_eq!(base["dimensions"]["font-size"].as_integer(), Some(12));
+    }
+
+    #[test]
+    fn defaults() {
+        let config = from_str(CONFIG, None).unwrap();
+        assert_eq!(config.dimensions.font_size, 12.);
+        assert!(config.sets("plot", "database"));
+        assert!(!config.sets("plot", "graph_type"));
+
+        let matches = config
+            .apply(command())
+            .unwrap()
+            .try_get_matches_from(["genominicus", "plot"])
+            .unwrap();
+        assert_eq!(matches.get_one::<String>("window").unwrap(), "10");
+        let plot = matches.subcommand_matches("plot").unwrap();
+        // Paths are relative to the configuration file, and make the option
+        // optional
+        assert_eq!(
+            Path::new(plot.get_one::<String>("database").unwrap()),
+            config.root.join("genomes.db")
+        );
+        assert_eq!(
+            plot.get_many::<String>("annotations")
+                .unwrap()
+                .collect::<Vec<_>>(),
+            ["links", "dids"]
+        );
+        assert_eq!(plot.get_one::<String>("graph_type"), None);
+
+        // The command line still takes precedence
+        let matches = config
+            .apply(command())
+            .unwrap()
+            .try_get_matches_from(["genominicus", "--window", "3", "plot", "-D", "other.db"])
+            .unwrap();
+        assert_eq!(matches.get_one::<String>("window").unwrap(), "3");
+        assert_eq!(
+            matches
+                .subcommand_matches("plot")
+                .unwrap()
+                .get_one::<String>("database")
+                .unwrap(),
+            "other.db"
+        );
+    }
+
+    #[test]
+    fn presets() {
+        let config = from_str(CONFIG, Some("paper")).unwrap();
+        let matches = config
+            .apply(command())
+            .unwrap()
+            .try_get_matches_from(["genominicus", "plot"])
+            .unwrap();
+        assert_eq!(matches.get_one::<String>("window").unwrap(), "5");
+        let plot = matches.subcommand_matches("plot").unwrap();
+        assert_eq!(plot.get_one::<String>("graph_type").unwrap(), "barcode");
+        assert!(plot.get_one::<String>("database").is_some());
+
+        assert!(from_str(CONFIG, Some("poster")).is_err());
+    }
+
+    #[test]
+    fn unknown_option() {
+        let config = from_str("[plot]\ncolour = \"red\"\n", None).unwrap();
+        assert!(config.apply(command()).is_err());
+    }
+}
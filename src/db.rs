@@ -0,0 +1,853 @@
+use anyhow::*;
+use colored::Colorize;
+use log::*;
+use newick::*;
+use regex::Regex;
+use rusqlite::{Connection, OptionalExtension};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use syntesuite::genebook::{FamilyID, GeneBook, TailGene};
+
+use crate::utils::{remove_leaves, GeneCache, IdMapping, MissingPolicy};
+
+// The key/value table storing the parameters used to build a database
+const METADATA_TABLE: &str = "genominicus_metadata";
+const WINDOW_KEY: &str = "window";
+
+// `{id}` stands for the column holding the gene IDs
+const LEFTS_QUERY: &str = "select {id}, ancestral_id, direction, start, stop from genomes where species=? and chr=? and start<? order by start desc limit ?";
+const RIGHTS_QUERY: &str = "select {id}, ancestral_id, direction, start, stop from genomes where species=? and chr=? and start>? order by start asc limit ?";
+
+pub struct SpeciesSummary {
+    pub species: String,
+    pub chromosomes: usize,
+    pub genes: usize,
+    /// The number of distinct families among the genes of the species
+    pub families: usize,
+}
+
+pub struct DbSummary {
+    pub species: Vec<SpeciesSummary>,
+    /// The number of distinct families in the whole database
+    pub families: usize,
+    /// The half-length of the syntenic context, see [`window`]
+    pub window: usize,
+}
+
+pub fn connect(db_file: &str) -> Result<Connection> {
+    if !db_file.starts_with("file:") && !std::path::Path::new(db_file).exists() {
+        bail!("database {} does not exist", db_file.bold().yellow())
+    }
+    Connection::open(db_file)
+        .with_context(|| anyhow!("failed to connect to {}", db_file.bold().yellow()))
+}
+
+/// A database living in memory for as long as this is not dropped
+pub struct TransientDb {
+    /// The URI under which the database can be opened
+    pub uri: String,
+    _keep_alive: Connection,
+}
+
+/// Build an in-memory database from the given families and genomes, as
+/// `build-database` would do; if `species` is set, only the genomes of these
+/// species are processed
+pub fn transient(
+    families: &[String],
+    genome_files: &[String],
+    species_pattern: &str,
+    id_type: &str,
+    id_pattern: &str,
+    window: usize,
+    species: Option<&HashSet<String>>,
+) -> Result<TransientDb> {
+    let genome_files = if let Some(species) = species {
+        let species_regex = Regex::new(species_pattern)
+            .with_context(|| anyhow!("{} is not a valid regex", species_pattern.yellow().bold()))?;
+        // Files whose species can not be told are kept, to be safe
+        list_files(genome_files)?
+            .into_iter()
+            .filter(|f| {
+                species_from_filename(f, &species_regex).is_none_or(|s| species.contains(&s))
+            })
+            .collect()
+    } else {
+        genome_files.to_vec()
+    };
+
+    let uri = format!(
+        "file:genominicus-{}?mode=memory&cache=shared",
+        std::process::id()
+    );
+    // A shared in-memory database is dropped as soon as its last connection is closed
+    let keep_alive = Connection::open(&uri)?;
+    build(
+        families,
+        &genome_files,
+        &uri,
+        species_pattern,
+        id_type,
+        id_pattern,
+        window,
+    )?;
+    Ok(TransientDb {
+        uri,
+        _keep_alive: keep_alive,
+    })
+}
+
+/// Expand the given files and directories into a list of files, as done by
+/// `build-database`; the content of directories is sorted by name
+pub fn list_files(paths: &[String]) -> Result<Vec<String>> {
+    let mut r = Vec::new();
+    for name in paths {
+        let path = std::path::Path::new(name);
+        if path.is_dir() {
+            let mut entries = path
+                .read_dir()
+                .with_context(|| anyhow!("while reading {}", name))?
+                .map(|entry| Ok(entry?.path().to_str().unwrap().to_owned()))
+                .collect::<Result<Vec<_>>>()?;
+            entries.sort();
+            r.extend(entries);
+        } else {
+            r.push(name.to_owned());
+        }
+    }
+    Ok(r)
+}
+
+/// Extract the species name from a genome file name, as done by `build-database`
+pub fn species_from_filename(filename: &str, species_regex: &Regex) -> Option<String> {
+    std::path::Path::new(filename)
+        .file_name()
+        .and_then(|f| f.to_str())
+        .and_then(|f| species_regex.captures(f))
+        .and_then(|c| c.name("species"))
+        .map(|s| s.as_str().to_owned())
+}
+
+pub fn summarize(db_file: &str) -> Result<DbSummary> {
+    let conn = connect(db_file)?;
+    let species = conn
+        .prepare(
+            "SELECT species, COUNT(DISTINCT chr), COUNT(*), COUNT(DISTINCT ancestral_id) \
+             FROM genomes GROUP BY species ORDER BY species",
+        )?
+        .query_map([], |r| {
+            rusqlite::Result::Ok(SpeciesSummary {
+                species: r.get(0)?,
+                chromosomes: r.get(1)?,
+                genes: r.get(2)?,
+                families: r.get(3)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    let families = conn.query_row(
+        "SELECT COUNT(DISTINCT ancestral_id) FROM genomes",
+        [],
+        |r| r.get(0),
+    )?;
+
+    let window = window_of(&conn)?;
+
+    Ok(DbSummary {
+        species,
+        families,
+        window,
+    })
+}
+
+/// Record `window` as the one used to build the database of `conn`
+fn store_window(conn: &Connection, window: usize) -> Result<()> {
+    conn.execute(
+        &format!("CREATE TABLE IF NOT EXISTS {METADATA_TABLE} (key TEXT PRIMARY KEY, value TEXT)"),
+        [],
+    )?;
+    conn.execute(
+        &format!("INSERT OR REPLACE INTO {METADATA_TABLE} (key, value) VALUES (?, ?)"),
+        rusqlite::params![WINDOW_KEY, window.to_string()],
+    )?;
+    Ok(())
+}
+
+/// The window of the database of `conn`, as recorded when building it; or, for
+/// databases predating this record, as inferred from its longest stored tail
+fn window_of(conn: &Connection) -> Result<usize> {
+    let has_metadata = conn.query_row(
+        "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type='table' AND name=?)",
+        [METADATA_TABLE],
+        |r| r.get::<_, bool>(0),
+    )?;
+    if has_metadata {
+        let stored = conn
+            .query_row(
+                &format!("SELECT value FROM {METADATA_TABLE} WHERE key=?"),
+                [WINDOW_KEY],
+                |r| r.get::<_, String>(0),
+            )
+            .optional()?;
+        if let Some(window) = stored {
+            return window
+                .parse()
+                .with_context(|| anyhow!("invalid stored window: {}", window));
+        }
+    }
+
+    // Tails are stored as `.`-separated lists of stranded family IDs
+    conn.prepare("SELECT left_tail_ids, right_tail_ids FROM genomes")?
+        .query_map([], |r| {
+            rusqlite::Result::Ok((r.get::<_, String>(0)?, r.get::<_, String>(1)?))
+        })?
+        .map(|tails| {
+            tails.map(|(left, right)| {
+                let count = |tail: &str| {
+                    if tail.is_empty() {
+                        0
+                    } else {
+                        tail.split('.').count()
+                    }
+                };
+                count(&left).max(count(&right))
+            })
+        })
+        .try_fold(0, |ax, w| w.map(|w| ax.max(w)))
+        .map_err(Into::into)
+}
+
+/// The half-length of the syntenic context stored in `db_file`
+pub fn window(db_file: &str) -> Result<usize> {
+    window_of(&connect(db_file)?)
+}
+
+/// Build a database from the given families and genomes, as `build-database`
+/// does
+pub fn build(
+    families: &[String],
+    genome_files: &[String],
+    db_file: &str,
+    species_pattern: &str,
+    id_type: &str,
+    id_pattern: &str,
+    window: usize,
+) -> Result<()> {
+    syntesuite::dbmaker::db_from_files(
+        families,
+        genome_files,
+        db_file,
+        species_pattern,
+        id_type,
+        id_pattern,
+        window as isize,
+    )?;
+    store_window(&connect(db_file)?, window)
+}
+
+pub fn inspect(db_file: &str, genome_files: &[String], species_pattern: &str) -> Result<()> {
+    let summary = summarize(db_file)?;
+
+    println!(
+        "{} species, window size: {}",
+        summary.species.len().to_string().bold(),
+        summary.window.to_string().bold()
+    );
+    println!(
+        "{:<30} {:>12} {:>12} {:>12}",
+        "species", "chromosomes", "genes", "families"
+    );
+    for s in summary.species.iter() {
+        println!(
+            "{:<30} {:>12} {:>12} {:>12}",
+            s.species.bright_white().bold(),
+            s.chromosomes,
+            s.genes,
+            s.families
+        );
+    }
+    println!(
+        "{:<30} {:>12} {:>12} {:>12}",
+        "total".bold(),
+        summary.species.iter().map(|s| s.chromosomes).sum::<usize>(),
+        summary.species.iter().map(|s| s.genes).sum::<usize>(),
+        summary.families
+    );
+
+    if !genome_files.is_empty() {
+        let species_regex = Regex::new(species_pattern)
+            .with_context(|| anyhow!("{} is not a valid regex", species_pattern.yellow().bold()))?;
+        let present = summary
+            .species
+            .iter()
+            .map(|s| s.species.as_str())
+            .collect::<HashSet<_>>();
+        for f in list_files(genome_files)? {
+            if let Some(species) = species_from_filename(&f, &species_regex) {
+                if !present.contains(species.as_str()) {
+                    eprintln!(
+                        "{} {} ({}) is absent from the database; its IDs may not have been matched by the ID pattern",
+                        "warning:".yellow().bold(),
+                        species.yellow().bold(),
+                        f
+                    );
+                }
+            } else {
+                eprintln!(
+                    "{} no species name could be extracted from {}",
+                    "warning:".yellow().bold(),
+                    f.yellow().bold()
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Serialize, Clone)]
+pub struct Neighbour {
+    pub id: String,
+    pub family: FamilyID,
+    pub strand: String,
+    pub start: usize,
+    pub stop: usize,
+}
+
+#[derive(Serialize)]
+pub struct Neighbourhood {
+    pub id: String,
+    pub species: String,
+    pub chr: String,
+    pub start: usize,
+    pub strand: String,
+    pub family: FamilyID,
+    /// The left neighbours, from the closest to the farthest
+    pub lefts: Vec<Neighbour>,
+    /// The right neighbours, from the closest to the farthest
+    pub rights: Vec<Neighbour>,
+}
+
+fn neighbours(
+    conn: &Connection,
+    query: &str,
+    id_column: &str,
+    species: &str,
+    chr: &str,
+    pos: usize,
+    window: usize,
+) -> Result<Vec<Neighbour>> {
+    conn.prepare_cached(&query.replace("{id}", id_column))?
+        .query_map(rusqlite::params![species, chr, pos, window], |r| {
+            rusqlite::Result::Ok(Neighbour {
+                id: r.get(0)?,
+                family: r.get(1)?,
+                strand: r.get(2)?,
+                start: r.get(3)?,
+                stop: r.get(4)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(Into::into)
+}
+
+/// Fetch the `window` closest neighbours on each side of the gene `id`
+pub fn neighbourhood(
+    book: &GeneBook,
+    conn: &Connection,
+    id_column: &str,
+    id: &str,
+    window: usize,
+) -> Result<Neighbourhood> {
+    let gene = book
+        .get(id)
+        .with_context(|| anyhow!("{} not found in the database", id.bold().yellow()))?;
+    Ok(Neighbourhood {
+        lefts: neighbours(
+            conn,
+            LEFTS_QUERY,
+            id_column,
+            &gene.species,
+            &gene.chr,
+            gene.pos,
+            window,
+        )?,
+        rights: neighbours(
+            conn,
+            RIGHTS_QUERY,
+            id_column,
+            &gene.species,
+            &gene.chr,
+            gene.pos,
+            window,
+        )?,
+        id: id.to_owned(),
+        species: gene.species,
+        chr: gene.chr,
+        start: gene.pos,
+        strand: gene.strand.to_string(),
+        family: gene.family,
+    })
+}
+
+impl Neighbour {
+    /// A one-line description of this gene, located on `chr`
+    pub fn describe(&self, chr: &str) -> String {
+        format!(
+            "{} – family {} ({}) – {}:{}-{}",
+            self.id, self.family, self.strand, chr, self.start, self.stop
+        )
+    }
+}
+
+/// For each leaf, the neighbours matching its left and right landscapes in the
+/// gene cache, in the same order
+pub type NeighbourCache = HashMap<String, (Vec<Neighbour>, Vec<Neighbour>)>;
+
+/// Fetch the genes making up the landscapes of `genes`; as these may have
+/// been flipped when building the cache, each side is matched against the
+/// landscapes by family.
+pub fn make_neighbours_cache(
+    conn: &Connection,
+    id_column: &str,
+    genes: &GeneCache,
+) -> Result<NeighbourCache> {
+    fn matches(neighbours: &[Neighbour], landscape: &[TailGene]) -> bool {
+        neighbours.len() >= landscape.len()
+            && neighbours
+                .iter()
+                .zip(landscape.iter())
+                .all(|(n, tg)| n.family == tg.family)
+    }
+
+    let mut r = NeighbourCache::new();
+    for (name, gene) in genes.iter() {
+        let window = gene.left_landscape.len().max(gene.right_landscape.len());
+        let mut lefts = neighbours(
+            conn,
+            LEFTS_QUERY,
+            id_column,
+            &gene.species,
+            &gene.chr,
+            gene.pos,
+            window,
+        )?;
+        let mut rights = neighbours(
+            conn,
+            RIGHTS_QUERY,
+            id_column,
+            &gene.species,
+            &gene.chr,
+            gene.pos,
+            window,
+        )?;
+        if !matches(&lefts, &gene.left_landscape) {
+            std::mem::swap(&mut lefts, &mut rights);
+        }
+        if matches(&lefts, &gene.left_landscape) && matches(&rights, &gene.right_landscape) {
+            lefts.truncate(gene.left_landscape.len());
+            rights.truncate(gene.right_landscape.len());
+            r.insert(name.to_owned(), (lefts, rights));
+        } else {
+            debug!(
+                "unable to match the neighbours of {} to its landscape",
+                name
+            );
+        }
+    }
+    Ok(r)
+}
+
+/// A tree leaf that could not be found in the database
+pub struct MissingLeaf {
+    pub node: usize,
+    pub name: String,
+    /// The ID looked for in the database
+    pub id: String,
+    /// The closest IDs found in the database
+    pub suggestions: Vec<String>,
+}
+
+/// Find the leaves of `tree` that are absent from the database, along with
+/// similar IDs that may correspond to typos or ID format mismatches
+pub fn check_leaves(
+    tree: &NewickTree,
+    db_file: &str,
+    id_column: &str,
+    ids: &IdMapping,
+) -> Result<Vec<MissingLeaf>> {
+    const MAX_SUGGESTIONS: usize = 3;
+    const MIN_SIMILARITY: f64 = 0.8;
+    const MAX_LENGTH_DIFFERENCE: usize = 3;
+
+    let conn = connect(db_file)?;
+    let mut is_known = conn.prepare(&format!(
+        "SELECT EXISTS(SELECT 1 FROM genomes WHERE {id_column}=?)"
+    ))?;
+    let mut missing = Vec::new();
+    for l in tree.leaves() {
+        if let Some(name) = tree.name(l) {
+            let id = ids.db_id(name);
+            if !is_known.query_row([&id], |r| r.get::<_, bool>(0))? {
+                missing.push(MissingLeaf {
+                    node: l,
+                    name: name.to_owned(),
+                    id,
+                    suggestions: Vec::new(),
+                });
+            }
+        }
+    }
+
+    if !missing.is_empty() {
+        // Only IDs of similar lengths are compared, so that the scan of the
+        // database does not grow with the number of missing leaves
+        let mut by_length = std::collections::BTreeMap::<usize, Vec<usize>>::new();
+        for (i, m) in missing.iter().enumerate() {
+            by_length.entry(m.id.chars().count()).or_default().push(i);
+        }
+        let mut all_ids = conn.prepare(&format!("SELECT {id_column} FROM genomes"))?;
+        let mut candidates = vec![Vec::<(f64, String)>::new(); missing.len()];
+        for id in all_ids.query_map([], |r| r.get::<_, String>(0))? {
+            let id = id?;
+            let length = id.chars().count();
+            for i in by_length
+                .range(
+                    length.saturating_sub(MAX_LENGTH_DIFFERENCE)..=length + MAX_LENGTH_DIFFERENCE,
+                )
+                .flat_map(|(_, is)| is.iter())
+            {
+                let similarity = strsim::jaro_winkler(&missing[*i].id, &id);
+                if similarity >= MIN_SIMILARITY {
+                    candidates[*i].push((similarity, id.clone()));
+                }
+            }
+        }
+        for (m, mut c) in missing.iter_mut().zip(candidates) {
+            c.sort_by(|a, b| b.0.total_cmp(&a.0));
+            m.suggestions = c
+                .into_iter()
+                .take(MAX_SUGGESTIONS)
+                .map(|(_, id)| id)
+                .collect();
+        }
+    }
+
+    Ok(missing)
+}
+
+/// Ensure that all the leaves of `t` are present in the database, reporting
+/// the ones that are not and applying `policy` to them; return the leaves to
+/// render as placeholders
+pub fn apply_missing_policy(
+    t: &mut NewickTree,
+    database: &str,
+    id_column: &str,
+    ids: &IdMapping,
+    policy: MissingPolicy,
+) -> Result<HashSet<String>> {
+    let missing = check_leaves(t, database, id_column, ids)?;
+    if missing.is_empty() {
+        return Ok(HashSet::new());
+    }
+
+    for m in missing.iter() {
+        eprintln!(
+            "{} {}{} not found in {}{}",
+            if policy == MissingPolicy::Fail {
+                "error:".red().bold()
+            } else {
+                "warning:".yellow().bold()
+            },
+            m.name.bold().yellow(),
+            if m.id != m.name {
+                format!(" (as {})", m.id)
+            } else {
+                String::new()
+            },
+            database,
+            if m.suggestions.is_empty() {
+                String::new()
+            } else {
+                format!("; did you mean {}?", m.suggestions.join(", "))
+            }
+        );
+    }
+
+    match policy {
+        MissingPolicy::Fail => bail!("{} leaves not found in {}", missing.len(), database),
+        MissingPolicy::Skip => {
+            if missing.len() == t.leaves().count() {
+                bail!("no leaf remaining in the tree after removing the missing ones")
+            }
+            remove_leaves(t, &missing.iter().map(|m| m.node).collect::<Vec<_>>());
+            Ok(HashSet::new())
+        }
+        MissingPolicy::Placeholder => Ok(missing.into_iter().map(|m| m.name).collect()),
+    }
+}
+
+fn colorize_family(family: FamilyID) -> colored::ColoredString {
+    let (r, g, b) = crate::utils::gene2color(&family.to_ne_bytes()).to_percent();
+    family
+        .to_string()
+        .truecolor((255. * r) as u8, (255. * g) as u8, (255. * b) as u8)
+}
+
+pub fn query(
+    db_file: &str,
+    id_column: &str,
+    ids: &[String],
+    window: usize,
+    json: bool,
+) -> Result<()> {
+    let book = GeneBook::inline(db_file, window, id_column)?;
+    let conn = connect(db_file)?;
+
+    // Unknown IDs are reported, but do not prevent the others from being displayed
+    let mut neighbourhoods = Vec::new();
+    for id in ids {
+        match neighbourhood(&book, &conn, id_column, id, window) {
+            Result::Ok(n) => neighbourhoods.push(n),
+            Err(e) => eprintln!("{} {:#}", "warning:".yellow().bold(), e),
+        }
+    }
+    if neighbourhoods.is_empty() {
+        bail!("none of the queried genes was found in the database");
+    }
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&neighbourhoods)?);
+        return Ok(());
+    }
+
+    for n in neighbourhoods.iter() {
+        let (r, g, b) = crate::utils::name2color(&n.species).to_percent();
+        println!(
+            "{} {}/{}:{} ({}) family {}",
+            n.id.bold(),
+            n.species
+                .truecolor((255. * r) as u8, (255. * g) as u8, (255. * b) as u8)
+                .bold(),
+            n.chr,
+            n.start,
+            n.strand,
+            colorize_family(n.family).bold()
+        );
+        for (side, neighbours) in [("left", &n.lefts), ("right", &n.rights)] {
+            for (i, neighbour) in neighbours.iter().enumerate() {
+                println!(
+                    "  {:>5} {:>3}  {:<24} {:>10}  {}",
+                    side,
+                    i + 1,
+                    neighbour.id,
+                    colorize_family(neighbour.family),
+                    neighbour.strand,
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Parse gene families as done by `build-database`, _i.e._ one family per file,
+/// numbered from 1 in the order of the files; only genes sharing the same
+/// number are meaningful, not the numbers themselves
+pub fn read_families(families: &[String]) -> Result<HashMap<String, FamilyID>> {
+    let mut id2family = HashMap::new();
+    for (i, f) in list_files(families)?.iter().enumerate() {
+        for id in std::fs::read_to_string(f)
+            .with_context(|| anyhow!("failed to read {}", f.bold().yellow()))?
+            .split_whitespace()
+        {
+            id2family.insert(id.to_owned(), i + 1);
+        }
+    }
+    Ok(id2family)
+}
+
+fn remap_tail(tail: &str, remap: &HashMap<FamilyID, FamilyID>) -> Result<String> {
+    if tail.is_empty() {
+        return Ok(String::new());
+    }
+    tail.split('.')
+        .map(|g| {
+            let (strand, family) = g.split_at(1);
+            let family = family
+                .parse::<FamilyID>()
+                .with_context(|| anyhow!("invalid tail gene: {}", g))?;
+            let family = remap
+                .get(&family)
+                .with_context(|| anyhow!("unknown family: {}", family))?;
+            Ok(format!("{}{}", strand, family))
+        })
+        .collect::<Result<Vec<_>>>()
+        .map(|genes| genes.join("."))
+}
+
+/// Insert or replace the genomes of the given species in an existing database,
+/// leaving the other species untouched.
+///
+/// The new genomes are first processed as by `build-database` in a temporary
+/// database; their family IDs are then translated to the ones already used in
+/// `db_file` for the families sharing genes with the existing species.
+pub fn update(
+    db_file: &str,
+    families: &[String],
+    genome_files: &[String],
+    species_pattern: &str,
+    id_type: &str,
+    id_pattern: &str,
+    window: usize,
+) -> Result<()> {
+    let mut conn = connect(db_file)?;
+    let existing_window = window_of(&conn)?;
+    if existing_window != 0 && existing_window != window {
+        eprintln!(
+            "{} {} was built with a window of {}, but the new genomes will use {}",
+            "warning:".yellow().bold(),
+            db_file.bold(),
+            existing_window,
+            window
+        );
+    }
+
+    let tmp_db = std::env::temp_dir().join(format!("genominicus-update-{}.db", std::process::id()));
+    let tmp_db = tmp_db.to_str().unwrap();
+    let r = (|| -> Result<()> {
+        info!("Processing the new genomes...");
+        syntesuite::dbmaker::db_from_files(
+            families,
+            genome_files,
+            tmp_db,
+            species_pattern,
+            id_type,
+            id_pattern,
+            window as isize,
+        )?;
+        let tmp_conn = connect(tmp_db)?;
+        let new_species = tmp_conn
+            .prepare("SELECT DISTINCT species FROM genomes")?
+            .query_map([], |r| r.get::<_, String>(0))?
+            .collect::<Result<HashSet<_>, _>>()?;
+        if new_species.is_empty() {
+            bail!("no gene from the provided genomes could be found in the families")
+        }
+
+        // Families are matched through their genes, so that the numbering of
+        // the temporary database does not have to match the one of `families`
+        info!("Mapping families...");
+        let id2family = read_families(families)?;
+        let mut family2existing = HashMap::<FamilyID, FamilyID>::new();
+        let mut next_family = conn.query_row(
+            "SELECT COALESCE(MAX(ancestral_id), 0) FROM genomes",
+            [],
+            |r| r.get::<_, FamilyID>(0),
+        )? + 1;
+        {
+            let mut existing = conn.prepare("SELECT species, id, ancestral_id FROM genomes")?;
+            let mut rows = existing.query([])?;
+            while let Some(row) = rows.next()? {
+                if new_species.contains(&row.get::<_, String>(0)?) {
+                    continue;
+                }
+                if let Some(family) = id2family.get(&row.get::<_, String>(1)?) {
+                    family2existing
+                        .entry(*family)
+                        .or_insert(row.get::<_, FamilyID>(2)?);
+                }
+            }
+        }
+        let mut remap = HashMap::<FamilyID, FamilyID>::new();
+        let mut new_families = 0;
+        {
+            let mut new_rows = tmp_conn.prepare("SELECT id, ancestral_id FROM genomes")?;
+            let mut rows = new_rows.query([])?;
+            while let Some(row) = rows.next()? {
+                let id = row.get::<_, String>(0)?;
+                let family = *id2family
+                    .get(&id)
+                    .with_context(|| anyhow!("{} not found in the families", id.bold().yellow()))?;
+                let existing = *family2existing.entry(family).or_insert_with(|| {
+                    new_families += 1;
+                    next_family += 1;
+                    next_family - 1
+                });
+                remap.insert(row.get::<_, FamilyID>(1)?, existing);
+            }
+        }
+        info!("{} new families", new_families);
+
+        info!("Updating {}...", db_file.bold());
+        let tx = conn.transaction()?;
+        for species in new_species.iter() {
+            let removed = tx.execute("DELETE FROM genomes WHERE species=?", [species])?;
+            if removed > 0 {
+                info!("Replacing {}", species.bold());
+            } else {
+                info!("Inserting {}", species.bold());
+            }
+        }
+        {
+            let mut insert = tx.prepare(
+                "INSERT INTO genomes (species, chr, ancestral_id, id, start, stop, direction, left_tail_ids, right_tail_ids) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            )?;
+            let mut new_rows = tmp_conn.prepare(
+                "SELECT species, chr, ancestral_id, id, start, stop, direction, left_tail_ids, right_tail_ids FROM genomes",
+            )?;
+            let mut rows = new_rows.query([])?;
+            while let Some(row) = rows.next()? {
+                let family = row.get::<_, FamilyID>(2)?;
+                insert.execute(rusqlite::params![
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    remap
+                        .get(&family)
+                        .with_context(|| anyhow!("unknown family: {}", family))?,
+                    row.get::<_, String>(3)?,
+                    row.get::<_, usize>(4)?,
+                    row.get::<_, usize>(5)?,
+                    row.get::<_, String>(6)?,
+                    remap_tail(&row.get::<_, String>(7)?, &remap)?,
+                    remap_tail(&row.get::<_, String>(8)?, &remap)?,
+                ])?;
+            }
+        }
+        // As for older databases, the window is the one of the longest tails
+        store_window(&tx, existing_window.max(window))?;
+        tx.commit()?;
+        Ok(())
+    })();
+    let _ = std::fs::remove_file(tmp_db);
+    r
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remap_tails() {
+        let remap = HashMap::from([(2, 20), (56, 7)]);
+        assert_eq!(remap_tail("", &remap).unwrap(), "");
+        assert_eq!(remap_tail("-56", &remap).unwrap(), "-7");
+        assert_eq!(remap_tail("-56.+2", &remap).unwrap(), "-7.+20");
+        assert!(remap_tail("+3", &remap).is_err());
+        assert!(remap_tail("+x", &remap).is_err());
+    }
+
+    #[test]
+    fn stored_window() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE genomes (left_tail_ids TEXT, right_tail_ids TEXT);
+             INSERT INTO genomes VALUES ('', '+1.-2'), ('-3.+4.+5', '');",
+        )
+        .unwrap();
+        // Databases predating the metadata table fall back to their tails
+        assert_eq!(window_of(&conn).unwrap(), 3);
+        store_window(&conn, 15).unwrap();
+        assert_eq!(window_of(&conn).unwrap(), 15);
+        store_window(&conn, 4).unwrap();
+        assert_eq!(window_of(&conn).unwrap(), 4);
+    }
+}
@@ -0,0 +1,253 @@
+use anyhow::*;
+use colored::Colorize;
+use log::*;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+
+/// The gene family file formats understood by `build-database`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FamilyFormat {
+    /// One family per file, as whitespace-separated gene IDs
+    Native,
+    /// An OrthoFinder `Orthogroups.tsv` file
+    OrthoFinder,
+    /// An Ensembl Compara homology dump; families are the connected components
+    /// of the homology graph
+    Compara,
+}
+impl TryFrom<&str> for FamilyFormat {
+    type Error = anyhow::Error;
+
+    fn try_from(s: &str) -> Result<Self> {
+        match s {
+            "native" => Ok(FamilyFormat::Native),
+            "orthofinder" => Ok(FamilyFormat::OrthoFinder),
+            "compara" => Ok(FamilyFormat::Compara),
+            _ => bail!("unknown family format: {}", s),
+        }
+    }
+}
+
+const COMPARA_GENE: &str = "gene_stable_id";
+const COMPARA_HOMOLOGY: &str = "homology_gene_stable_id";
+
+fn header(filename: &str) -> Result<String> {
+    let mut header = String::new();
+    BufReader::new(
+        std::fs::File::open(filename)
+            .with_context(|| anyhow!("failed to open {}", filename.bold().yellow()))?,
+    )
+    .read_line(&mut header)?;
+    Ok(header)
+}
+
+/// Guess the format of a family file from its header
+pub fn detect(filename: &str) -> Result<FamilyFormat> {
+    if std::path::Path::new(filename).is_dir() {
+        return Ok(FamilyFormat::Native);
+    }
+    let header = header(filename)?;
+    let columns = header.trim_end().split('\t').collect::<Vec<_>>();
+    Ok(if columns.first() == Some(&"Orthogroup") {
+        FamilyFormat::OrthoFinder
+    } else if columns.contains(&COMPARA_GENE) && columns.contains(&COMPARA_HOMOLOGY) {
+        FamilyFormat::Compara
+    } else {
+        FamilyFormat::Native
+    })
+}
+
+fn read_orthofinder(filename: &str) -> Result<Vec<Vec<String>>> {
+    let mut families = Vec::new();
+    let file = BufReader::new(std::fs::File::open(filename)?);
+    for l in file.lines().skip(1) {
+        let l = l?;
+        let family = l
+            .split('\t')
+            .skip(1)
+            .flat_map(|cell| cell.split(','))
+            .map(str::trim)
+            .filter(|id| !id.is_empty())
+            .map(str::to_owned)
+            .collect::<Vec<_>>();
+        if !family.is_empty() {
+            families.push(family);
+        }
+    }
+    Ok(families)
+}
+
+fn read_compara(filename: &str) -> Result<Vec<Vec<String>>> {
+    fn find(parents: &mut [usize], mut x: usize) -> usize {
+        while parents[x] != x {
+            parents[x] = parents[parents[x]];
+            x = parents[x];
+        }
+        x
+    }
+
+    let columns = header(filename)?
+        .trim_end()
+        .split('\t')
+        .map(str::to_owned)
+        .collect::<Vec<_>>();
+    let gene_column = columns
+        .iter()
+        .position(|c| c == COMPARA_GENE)
+        .with_context(|| anyhow!("{}: missing column {}", filename, COMPARA_GENE))?;
+    let homology_column = columns
+        .iter()
+        .position(|c| c == COMPARA_HOMOLOGY)
+        .with_context(|| anyhow!("{}: missing column {}", filename, COMPARA_HOMOLOGY))?;
+
+    let mut ids = HashMap::<String, usize>::new();
+    let mut parents = Vec::<usize>::new();
+    let file = BufReader::new(std::fs::File::open(filename)?);
+    for (i, l) in file.lines().enumerate().skip(1) {
+        let l = l?;
+        let fields = l.split('\t').collect::<Vec<_>>();
+        let (Some(gene), Some(homolog)) = (fields.get(gene_column), fields.get(homology_column))
+        else {
+            bail!("{}:{}: missing fields", filename, i + 1);
+        };
+        let [gene, homolog] = [gene, homolog].map(|id| {
+            *ids.entry(id.to_string()).or_insert_with(|| {
+                parents.push(parents.len());
+                parents.len() - 1
+            })
+        });
+        let (a, b) = (find(&mut parents, gene), find(&mut parents, homolog));
+        parents[a] = b;
+    }
+
+    let mut families = HashMap::<usize, Vec<String>>::new();
+    for (id, i) in ids.into_iter() {
+        let root = find(&mut parents, i);
+        families.entry(root).or_default().push(id);
+    }
+    let mut families = families.into_values().collect::<Vec<_>>();
+    // Ensure a reproducible family numbering
+    families.iter_mut().for_each(|f| f.sort());
+    families.sort();
+    Ok(families)
+}
+
+/// Gene family files ready to be processed by `build-database`; families read
+/// from foreign formats are converted to temporary native files, one per
+/// family, that are removed once this is dropped.
+pub struct PreparedFamilies {
+    pub paths: Vec<String>,
+    scratch: Option<PathBuf>,
+}
+impl Drop for PreparedFamilies {
+    fn drop(&mut self) {
+        if let Some(scratch) = self.scratch.as_ref() {
+            let _ = std::fs::remove_dir_all(scratch);
+        }
+    }
+}
+
+/// Convert, if required, the given family files to the native format;
+/// `format` may be `None` to detect it for each file.
+pub fn prepare(families: &[String], format: Option<FamilyFormat>) -> Result<PreparedFamilies> {
+    let mut r = PreparedFamilies {
+        paths: Vec::new(),
+        scratch: None,
+    };
+
+    for filename in families {
+        let format = if let Some(format) = format {
+            format
+        } else {
+            detect(filename)?
+        };
+        let converted = match format {
+            FamilyFormat::Native => {
+                r.paths.push(filename.to_owned());
+                continue;
+            }
+            FamilyFormat::OrthoFinder => read_orthofinder(filename)?,
+            FamilyFormat::Compara => read_compara(filename)?,
+        };
+        info!(
+            "Read {} families from {} ({:?})",
+            converted.len(),
+            filename.bold(),
+            format
+        );
+
+        let scratch = r
+            .scratch
+            .get_or_insert_with(|| {
+                std::env::temp_dir().join(format!("genominicus-families-{}", std::process::id()))
+            })
+            .join(r.paths.len().to_string());
+        std::fs::create_dir_all(&scratch)
+            .with_context(|| anyhow!("failed to create {}", scratch.display()))?;
+        // Families are passed one file at a time rather than as a directory,
+        // so that their numbering does not depend on the directory listing
+        let width = converted.len().to_string().len();
+        for (i, family) in converted.iter().enumerate() {
+            let path = scratch.join(format!("{:0width$}", i));
+            let mut out = std::fs::File::create(&path)?;
+            writeln!(out, "{}", family.join(" "))?;
+            r.paths.push(path.to_str().unwrap().to_owned());
+        }
+    }
+
+    Ok(r)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_temp(name: &str, content: &str) -> String {
+        let path =
+            std::env::temp_dir().join(format!("genominicus-test-{}-{}", std::process::id(), name));
+        std::fs::write(&path, content).unwrap();
+        path.to_str().unwrap().to_owned()
+    }
+
+    #[test]
+    fn orthofinder() {
+        let filename = write_temp(
+            "Orthogroups.tsv",
+            "Orthogroup\tdrerio\thsapiens\n\
+             OG0000000\tDRE1, DRE2\tHSA1\n\
+             OG0000001\t\tHSA2\n\
+             OG0000002\t\t\n",
+        );
+        assert_eq!(detect(&filename).unwrap(), FamilyFormat::OrthoFinder);
+        let families = read_orthofinder(&filename).unwrap();
+        std::fs::remove_file(&filename).unwrap();
+        assert_eq!(families, vec![vec!["DRE1", "DRE2", "HSA1"], vec!["HSA2"]]);
+    }
+
+    #[test]
+    fn compara() {
+        let filename = write_temp(
+            "compara.tsv",
+            "gene_stable_id\tspecies\thomology_gene_stable_id\n\
+             E\ts\tD\n\
+             A\ts\tB\n\
+             C\ts\tB\n",
+        );
+        assert_eq!(detect(&filename).unwrap(), FamilyFormat::Compara);
+        let families = read_compara(&filename).unwrap();
+        std::fs::remove_file(&filename).unwrap();
+        assert_eq!(families, vec![vec!["A", "B", "C"], vec!["D", "E"]]);
+    }
+
+    #[test]
+    fn compara_missing_column() {
+        let filename = write_temp("compara-missing.tsv", "gene_stable_id\tspecies\nA\ts\n");
+        let r = read_compara(&filename);
+        std::fs::remove_file(&filename).unwrap();
+        assert!(r
+            .unwrap_err()
+            .to_string()
+            .contains("missing column homology_gene_stable_id"));
+    }
+}
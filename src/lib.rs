@@ -0,0 +1,55 @@
+//! Genominicus plots gene trees alongside the syntenic landscape of their
+//! leaves, as stored in a database built by [`syntesuite`].
+//!
+//! A typical pipeline reads a gene tree, gathers the landscapes of its leaves
+//! in a [`GeneCache`], picks a [`ColorMap`] for the gene families, then hands
+//! everything to one of the [`render`] modules:
+//!
+//! ```no_run
+//! use genominicus::{db, render, utils::*};
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let tree = newick::one_from_filename("tree.nhx")?;
+//! let genes = make_genes_cache(&tree, "genomes.db", "id", &IdMapping::new(None, None)?)?;
+//! let neighbours = db::make_neighbours_cache(&db::connect("genomes.db")?, "id", &genes)?;
+//! let colormap = make_colormap(&tree, &genes);
+//! let petmap = make_petnamemap(&tree, &genes);
+//!
+//! let mut out = std::fs::File::create("tree.svg")?;
+//! render::flat::render(
+//!     &tree,
+//!     &genes,
+//!     &neighbours,
+//!     &colormap,
+//!     &petmap,
+//!     &mut out,
+//!     &RenderSettings::default(),
+//! )?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! - [`utils`] builds the gene caches and the colormaps;
+//! - [`align`] computes the partial order alignments summarizing the landscapes
+//!   of a clade;
+//! - [`render`] draws flat and barcode SVGs, skeletons and HTML pages to any
+//!   [`std::io::Write`];
+//! - [`config`] reads the `genominicus.toml` defaults of the CLI;
+//! - [`db`], [`families`], [`annotate`], [`placement`] and [`serve`] back the
+//!   other subcommands of the CLI.
+//!
+//! With the `python` feature, the renderers are also exposed to Python, see
+//! the `python` module.
+pub mod align;
+pub mod annotate;
+pub mod config;
+pub mod db;
+pub mod families;
+pub mod placement;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod render;
+pub mod serve;
+pub mod utils;
+
+pub use utils::{ColorMap, GeneCache, PetnameMap, RenderSettings};
@@ -2,12 +2,13 @@ use anyhow::*;
 use anyhow::{Context, Result};
 use clap::*;
 use colored::Colorize;
+use genominicus::config::{Config, CONFIG_FILE};
+use genominicus::utils::*;
+use genominicus::{annotate, db, families, placement, render, serve};
 use log::*;
-use utils::*;
-
-mod align;
-mod render;
-mod utils;
+use newick::Newick;
+use std::collections::HashSet;
+use std::io::Write;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about)]
@@ -19,24 +20,83 @@ struct Args {
     #[clap(short, long, default_value_t = 15)]
     window: usize,
 
+    /// The configuration file setting the defaults of the options; otherwise, `genominicus.toml` is looked for in the current directory and its parents
+    #[clap(long, global = true)]
+    config: Option<String>,
+
+    /// The preset of the configuration file to use
+    #[clap(long, global = true)]
+    preset: Option<String>,
+
     #[clap(subcommand)]
     command: Commands,
 }
 
+#[derive(clap::Args, Debug)]
+struct LeafIds {
+    /// regex to extract the database ID from the gene tree leaf names; must contain a named capture group `id`
+    #[arg(long)]
+    leaf_pattern: Option<String>,
+
+    /// a two-column file mapping gene tree leaf names (or IDs extracted with `--leaf-pattern`) to database IDs
+    #[arg(long, value_hint = ValueHint::AnyPath)]
+    leaf_ids: Option<String>,
+}
+impl LeafIds {
+    fn mapping(&self) -> Result<IdMapping> {
+        IdMapping::new(self.leaf_pattern.as_deref(), self.leaf_ids.as_deref())
+    }
+}
+
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand, Debug)]
 enum Commands {
     /// Create a syntenic database of the provided genomes
     BuildDatabase {
         /// the files and/or directories containing the gene families to process
-        #[clap(long, required = true)]
+        #[clap(long, required = true, value_hint = ValueHint::AnyPath)]
         families: Vec<String>,
 
+        /// the format of the family files; `auto` detects OrthoFinder `Orthogroups.tsv` files and Ensembl Compara homology dumps from their header
+        #[clap(long, default_value = "auto", value_parser=["auto", "native", "orthofinder", "compara"])]
+        families_format: String,
+
         /// where to write the database
-        #[clap(long = "out", short = 'o')]
+        #[clap(long = "out", short = 'o', value_hint = ValueHint::AnyPath)]
         outfile: String,
 
         /// the directory containing the genomes to process; those can be either in the GFF3 or BED format, and may be gzipped
-        #[clap(long)]
+        #[clap(long, value_hint = ValueHint::AnyPath)]
+        genome_files: Vec<String>,
+
+        /// the features to extract from GFF files
+        #[clap(long, default_value_t = String::from("gene"))]
+        id_type: String,
+
+        /// regex to extract feature name from either a GFF ID attribute or a BED name field; must contain a named capture group `id`
+        #[clap(long, default_value_t = String::from("gene:(?P<id>.*)"))]
+        id_pattern: String,
+
+        /// regex to extract species name from genome files; must contain a named capture group `species`
+        #[clap(long, default_value_t = String::from("(?P<species>.*)\\.(gff3|bed|chrom)"))]
+        species_pattern: String,
+    },
+    /// Insert or replace the genomes of one or more species in an existing syntenic database
+    UpdateDatabase {
+        /// the database to update
+        #[clap(long = "database", short = 'D')]
+        database: String,
+
+        /// the files and/or directories containing the gene families to process, as used to build the database
+        #[clap(long, required = true)]
+        families: Vec<String>,
+
+        /// the format of the family files; `auto` detects OrthoFinder `Orthogroups.tsv` files and Ensembl Compara homology dumps from their header
+        #[clap(long, default_value = "auto", value_parser=["auto", "native", "orthofinder", "compara"])]
+        families_format: String,
+
+        /// the genomes to insert or replace; those can be either in the GFF3 or BED format, and may be gzipped
+        #[clap(long, required = true)]
         genome_files: Vec<String>,
 
         /// the features to extract from GFF files
@@ -53,22 +113,56 @@ enum Commands {
     },
     /// Render one or more gene trees, with their syntenic environment stored in the provided database
     Plot {
-        /// The gene trees to render
+        /// The gene trees to render; `-` reads a tree from the standard input
         #[arg(required = true)]
         files: Vec<String>,
 
-        /// Explicitely set an output file name
+        /// Explicitely set an output file name; `-` writes the plot to the standard output, which is also the default when reading from the standard input
         #[arg(short, long)]
         out: Option<String>,
 
         /// The database containing the syntenic environment of each gene, as built with `build-database`
-        #[arg(short = 'D', long = "database")]
-        database: String,
+        #[arg(
+            short = 'D',
+            long = "database",
+            required_unless_present = "genome_files",
+            value_hint = ValueHint::AnyPath
+        )]
+        database: Option<String>,
+
+        /// Instead of a database, the genomes to process on the fly; those can be either in the GFF3 or BED format, and may be gzipped
+        #[arg(long, conflicts_with = "database", requires = "families", value_hint = ValueHint::AnyPath)]
+        genome_files: Vec<String>,
+
+        /// the files and/or directories containing the gene families to process on the fly
+        #[arg(long, value_hint = ValueHint::AnyPath)]
+        families: Vec<String>,
+
+        /// the format of the family files; `auto` detects OrthoFinder `Orthogroups.tsv` files and Ensembl Compara homology dumps from their header
+        #[arg(long, default_value = "auto", value_parser=["auto", "native", "orthofinder", "compara"])]
+        families_format: String,
+
+        /// the features to extract from GFF files
+        #[arg(long, default_value_t = String::from("gene"))]
+        id_type: String,
 
-        /// The species tree
-        #[arg(short = 'S', required_if_eq("graph_type", "barcode"))]
+        /// regex to extract feature name from either a GFF ID attribute or a BED name field; must contain a named capture group `id`
+        #[arg(long, default_value_t = String::from("gene:(?P<id>.*)"))]
+        id_pattern: String,
+
+        /// regex to extract species name from genome files; must contain a named capture group `species`
+        #[arg(long, default_value_t = String::from("(?P<species>.*)\\.(gff3|bed|chrom)"))]
+        species_pattern: String,
+
+        /// The species tree; required for barcode plots, and shown as a side panel in HTML plots
+        #[arg(short = 'S', value_hint = ValueHint::AnyPath)]
         species_tree: Option<String>,
 
+        /// regex to extract the species from the gene tree leaf names lacking both an `S` attribute and a gene in the database; must contain a named capture group `species`
+        #[arg(long)]
+        leaf_species_pattern: Option<String>,
+
+        /// The type of plot; HTML plots embed the alignment of every inner node, use `serve` to align them on demand in huge trees
         #[arg(short = 'T', long = "type", default_value = "flat", value_parser=["flat", "html", "barcode", "skeleton"])]
         graph_type: String,
 
@@ -80,6 +174,9 @@ enum Commands {
         )]
         id_column: String,
 
+        #[clap(flatten)]
+        leaf_ids: LeafIds,
+
         /// If set, introduce a new set of color gradients at each duplication node
         #[arg(long)]
         colorize_per_duplication: bool,
@@ -92,19 +189,291 @@ enum Commands {
         #[arg(long = "filter-species")]
         filter_species_tree: bool,
 
-        /// Additional annotations to the plot
-        #[arg(long="annotations", value_delimiter = ',', value_parser=["links", "inner-nodes", "cs", "elc", "ellc", "dids", "nids"])]
+        /// Additional annotations to the plot; `misplaced` highlights the leaves whose syntenic context better fits another clade
+        #[arg(long="annotations", value_delimiter = ',', value_parser=["links", "inner-nodes", "misplaced", "cs", "elc", "ellc", "dids", "nids"])]
         annotations: Vec<String>,
 
+        /// How much better another clade must fit a leaf for the `misplaced` annotation to highlight it
+        #[arg(long, default_value_t = placement::DEFAULT_MARGIN)]
+        misplaced_margin: f32,
+
+        /// What to do with the leaves absent from the database
+        #[arg(long, default_value = "fail", value_parser=["fail", "skip", "placeholder"])]
+        missing: String,
+
+        /// The file format of the flat, barcode and skeleton plots; `tsv` and `json` export the duplications of barcode plots instead of drawing them
+        #[arg(long, default_value = "svg", value_parser=["svg", "pdf", "tsv", "json"])]
+        format: String,
+
+        /// Split flat plots into pages of this many leaves, written to numbered SVG
+        /// files or to a single PDF document
+        #[arg(long)]
+        page_size: Option<usize>,
+
+        /// The colors of the SVG and PDF plots; either `light`, `dark`, `print`, or a TOML theme file. HTML plots are not themed
+        #[arg(long, default_value = "light")]
+        theme: String,
+
+        /// A JSON file containing the `gene` and/or `locus` URL templates of the HTML links
+        #[arg(long, value_hint = ValueHint::AnyPath)]
+        link_templates: Option<String>,
+
+        /// The URL template of the gene names in the HTML output; `{gene}`, `{species}`, `{chr}` and `{family}` are replaced by the values of each gene
+        #[arg(long)]
+        gene_url: Option<String>,
+
+        /// The URL template of the gene loci in the HTML output; `{gene}`, `{species}`, `{chr}` and `{family}` are replaced by the values of each gene
+        #[arg(long)]
+        locus_url: Option<String>,
+
+        /// Do not link genes to external resources in the HTML output
+        #[arg(long, conflicts_with_all = ["link_templates", "gene_url", "locus_url"])]
+        no_links: bool,
+
         /// Display the plot after creation. If a program name is passed, use it to open the plot; otherwise use
         /// the system default
         #[arg(short = 'O', long)]
         open: Option<Option<String>>,
     },
+    /// Summarize the duplications of many gene trees along the branches of a species tree, as a heat-mapped species tree and a TSV table
+    Aggregate {
+        /// The gene trees to aggregate; each file may contain several trees
+        #[arg(required = true)]
+        files: Vec<String>,
+
+        /// The species tree onto which the duplications are mapped
+        #[arg(short = 'S', required = true, value_hint = ValueHint::AnyPath)]
+        species_tree: String,
+
+        /// regex to extract the species from the gene tree leaf names lacking an `S` attribute; must contain a named capture group `species`
+        #[arg(long)]
+        leaf_species_pattern: Option<String>,
+
+        /// The prefix of the output files; the plot and the table are written to `<out>-barcode.svg` and `<out>-barcode.tsv`
+        #[arg(short, long, default_value = "aggregate", value_hint = ValueHint::AnyPath)]
+        out: String,
+
+        /// The file format of the plot
+        #[arg(long, default_value = "svg", value_parser=["svg", "pdf"])]
+        format: String,
+
+        /// The colors of the plot; either `light`, `dark`, `print`, or a TOML theme file
+        #[arg(long, default_value = "light")]
+        theme: String,
+    },
+    /// Write out one or more gene trees as NHX, annotated with their syntenic information
+    Annotate {
+        /// The gene trees to annotate
+        #[arg(required = true)]
+        files: Vec<String>,
+
+        /// Explicitely set an output file name
+        #[arg(short, long)]
+        out: Option<String>,
+
+        /// The database containing the syntenic environment of each gene, as built with `build-database`
+        #[arg(short = 'D', long = "database")]
+        database: String,
+
+        #[arg(
+            short = 'I',
+            long = "id",
+            help = "the column name mapping to the IDs in the gene trees",
+            default_value = "id"
+        )]
+        id_column: String,
+
+        #[clap(flatten)]
+        leaf_ids: LeafIds,
+    },
+    /// List the leaves whose syntenic context fits another clade better than their sister clade
+    CheckPlacement {
+        /// The gene trees to check
+        #[arg(required = true)]
+        files: Vec<String>,
+
+        /// Write the report to this file instead of the standard output
+        #[arg(short, long)]
+        out: Option<String>,
+
+        /// The database containing the syntenic environment of each gene, as built with `build-database`
+        #[arg(short = 'D', long = "database")]
+        database: String,
+
+        #[arg(
+            short = 'I',
+            long = "id",
+            help = "the column name mapping to the IDs in the gene trees",
+            default_value = "id"
+        )]
+        id_column: String,
+
+        #[clap(flatten)]
+        leaf_ids: LeafIds,
+
+        /// How much better another clade must fit a leaf for it to be reported
+        #[arg(long, default_value_t = placement::DEFAULT_MARGIN)]
+        margin: f32,
+    },
+    /// Report on the content of a database built with `build-database`
+    Inspect {
+        /// The database to inspect
+        database: String,
+
+        /// The genome files used to build the database, to check that all of them were included
+        #[clap(long)]
+        genome_files: Vec<String>,
+
+        /// regex to extract species name from genome files; must contain a named capture group `species`
+        #[clap(long, default_value_t = String::from("(?P<species>.*)\\.(gff3|bed|chrom)"))]
+        species_pattern: String,
+    },
+    /// Display the syntenic environment of one or more genes; the neighbours are
+    /// found by their position on the chromosome, as many as the window of the
+    /// database unless `--window` is set
+    Query {
+        /// The genes to look up
+        #[arg(required = true)]
+        ids: Vec<String>,
+
+        /// The database containing the syntenic environment of each gene, as built with `build-database`
+        #[arg(short = 'D', long = "database")]
+        database: String,
+
+        #[arg(
+            short = 'I',
+            long = "id",
+            help = "the column name mapping to the queried IDs",
+            default_value = "id"
+        )]
+        id_column: String,
+
+        /// Output the result as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Browse a directory of gene trees through a local web server
+    Serve {
+        /// The directory containing the gene trees to serve
+        trees: String,
+
+        /// The database containing the syntenic environment of each gene, as built with `build-database`
+        #[arg(short = 'D', long = "database")]
+        database: String,
+
+        #[arg(
+            short = 'I',
+            long = "id",
+            help = "the column name mapping to the tree leaves",
+            default_value = "id"
+        )]
+        id_column: String,
+
+        #[command(flatten)]
+        leaf_ids: LeafIds,
+
+        /// What to do with the leaves absent from the database
+        #[arg(long, default_value = "placeholder", value_parser=["fail", "skip", "placeholder"])]
+        missing: String,
+
+        /// The species tree, shown as a side panel
+        #[arg(short = 'S')]
+        species_tree: Option<String>,
+
+        /// The port to listen to on localhost
+        #[arg(short, long, default_value_t = 8080)]
+        port: u16,
+
+        /// A JSON file containing the `gene` and/or `locus` URL templates of the links
+        #[arg(long)]
+        link_templates: Option<String>,
+
+        /// Open the browsing UI in the system default browser
+        #[arg(short = 'O', long)]
+        open: bool,
+    },
+}
+
+/// The file name standing for the standard input or output
+const STDIO: &str = "-";
+
+/// Read a tree from `filename`, or from the standard input if it is `-`
+fn read_tree(filename: &str) -> Result<newick::NewickTree> {
+    if filename == STDIO {
+        let mut content = String::new();
+        std::io::Read::read_to_string(&mut std::io::stdin(), &mut content)
+            .context("failed to read the standard input")?;
+        newick::one_from_string(content).context("failed to parse the standard input")
+    } else {
+        newick::one_from_filename(filename)
+            .with_context(|| anyhow!("failed to read `{}`", filename))
+    }
+}
+
+fn parse_families_format(format: &str) -> Result<Option<families::FamilyFormat>> {
+    if format == "auto" {
+        Ok(None)
+    } else {
+        families::FamilyFormat::try_from(format).map(Some)
+    }
+}
+
+/// The value of the option `--{name}` on the command line; as it selects the
+/// configuration providing the defaults of the parser, it is looked up before
+/// the actual parsing
+fn raw_option(name: &str) -> Option<String> {
+    let flag = format!("--{}", name);
+    let mut args = std::env::args().take_while(|a| a != "--");
+    while let Some(arg) = args.next() {
+        if arg == flag {
+            return args.next();
+        }
+        if let Some(value) = arg.strip_prefix(&flag).and_then(|v| v.strip_prefix('=')) {
+            return Some(value.to_owned());
+        }
+    }
+    None
+}
+
+/// Read the configuration file set on the command line, or found from the
+/// current directory
+fn load_config() -> Result<Config> {
+    let preset = raw_option("preset");
+    let preset = preset.as_deref();
+    let config_file = if let Some(config_file) = raw_option("config") {
+        Some(std::path::PathBuf::from(config_file))
+    } else {
+        Config::find(&std::env::current_dir()?)
+    };
+
+    if let Some(config_file) = config_file {
+        Config::from_file(&config_file, preset)
+    } else if let Some(preset) = preset {
+        bail!(
+            "preset `{}` requested, but no {} found",
+            preset,
+            CONFIG_FILE
+        )
+    } else {
+        Ok(Config::default())
+    }
 }
 
 fn main() -> Result<()> {
-    let args = Args::parse();
+    let config = load_config()?;
+    let mut cmd = config.apply(Args::command())?;
+    // A species tree set in the configuration is enough for barcode plots
+    if !config.sets("plot", "species_tree") {
+        cmd = cmd.mut_subcommand("plot", |plot| {
+            plot.mut_arg("species_tree", |a| {
+                a.required_if_eq("graph_type", "barcode")
+            })
+        });
+    }
+    let matches = cmd.get_matches_mut();
+    let window_is_set =
+        matches.value_source("window") == Some(clap::parser::ValueSource::CommandLine);
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
     buche::new()
         .timestamp(buche::Timestamp::Off)
         .verbosity(args.verbose.log_level_filter())
@@ -114,41 +483,171 @@ fn main() -> Result<()> {
     match args.command {
         Commands::BuildDatabase {
             families,
+            families_format,
             outfile,
             genome_files: gffs,
             id_type,
             id_pattern,
             species_pattern,
-        } => syntesuite::dbmaker::db_from_files(
-            &families,
+        } => db::build(
+            &families::prepare(&families, parse_families_format(&families_format)?)?.paths,
             &gffs,
             &outfile,
             &species_pattern,
             &id_type,
             &id_pattern,
-            args.window as isize,
+            args.window,
+        ),
+        Commands::UpdateDatabase {
+            database,
+            families,
+            families_format,
+            genome_files,
+            id_type,
+            id_pattern,
+            species_pattern,
+        } => db::update(
+            &database,
+            &families::prepare(&families, parse_families_format(&families_format)?)?.paths,
+            &genome_files,
+            &species_pattern,
+            &id_type,
+            &id_pattern,
+            args.window,
         ),
         Commands::Plot {
             files,
             out,
             database,
+            genome_files,
+            families,
+            families_format,
+            id_type,
+            id_pattern,
+            species_pattern,
             species_tree,
+            leaf_species_pattern,
             graph_type,
             id_column,
+            leaf_ids,
             colorize_per_duplication,
             colorize_all,
             filter_species_tree,
             annotations,
+            misplaced_margin,
+            missing,
+            format,
+            page_size,
+            theme,
+            link_templates,
+            gene_url,
+            locus_url,
+            no_links,
             open,
         } => {
-            let mut render_settings = RenderSettings::default();
+            let missing = MissingPolicy::try_from(missing.as_str())?;
+            let ids = leaf_ids.mapping()?;
+            let leaf_species_pattern = leaf_species_pattern
+                .as_deref()
+                .map(genominicus::utils::leaf_species_pattern)
+                .transpose()?;
+            // Without a database, the provided genomes are processed on the fly
+            let transient_db = if database.is_none()
+                && matches!(graph_type.as_str(), "flat" | "html")
+            {
+                // Only the genomes of the species featured in the trees are
+                // needed, if those can be told beforehand
+                let species = if files.iter().any(|f| f == STDIO) {
+                    None
+                } else {
+                    files
+                        .iter()
+                        .map(|f| {
+                            read_tree(f).map(|t| leaf_species(&t, leaf_species_pattern.as_ref()))
+                        })
+                        .collect::<Result<Option<Vec<_>>>>()?
+                        .map(|species| species.into_iter().flatten().collect::<HashSet<_>>())
+                };
+                Some(db::transient(
+                    &families::prepare(&families, parse_families_format(&families_format)?)?.paths,
+                    &genome_files,
+                    &species_pattern,
+                    &id_type,
+                    &id_pattern,
+                    args.window,
+                    species.as_ref(),
+                )?)
+            } else {
+                None
+            };
+            let database = database
+                .or_else(|| transient_db.as_ref().map(|db| db.uri.clone()))
+                .unwrap_or_default();
+            let mut render_settings = RenderSettings {
+                dimensions: config.dimensions,
+                theme: render::theme::Theme::from_name_or_file(&theme)?,
+                ..Default::default()
+            };
+            let mut show_misplaced = false;
             for annotation in annotations {
                 match annotation.as_str() {
                     "links" => render_settings.links = true,
                     "inner-nodes" => render_settings.inner_tags = true,
+                    "misplaced" => show_misplaced = true,
                     _ => render_settings.node_annotations.push(annotation),
                 }
             }
+            if no_links {
+                render_settings.link_templates = LinkTemplates::none();
+            } else {
+                if let Some(link_templates) = link_templates {
+                    render_settings.link_templates = LinkTemplates::from_file(&link_templates)?;
+                }
+                if gene_url.is_some() {
+                    render_settings.link_templates.gene = gene_url;
+                }
+                if locus_url.is_some() {
+                    render_settings.link_templates.locus = locus_url;
+                }
+            }
+
+            let species_tree = species_tree
+                .as_ref()
+                .map(|species_tree| {
+                    newick::one_from_filename(species_tree)
+                        .with_context(|| anyhow!("failed to read `{}`", species_tree))
+                })
+                .transpose()?;
+
+            if files.iter().filter(|f| *f == STDIO).count() > 1 {
+                bail!("the standard input can only be read once");
+            }
+            let to_stdout = |filename: &str| match out.as_deref() {
+                Some(out) => out == STDIO,
+                None => filename == STDIO,
+            };
+            if files.len() > 1 && files.iter().any(|f| to_stdout(f)) {
+                bail!("only a single plot can be written to the standard output");
+            }
+            if open.is_some() && files.iter().any(|f| to_stdout(f)) {
+                bail!("a plot written to the standard output can not be opened");
+            }
+            if page_size.is_some() && files.iter().any(|f| to_stdout(f)) {
+                bail!("a paginated plot can not be written to the standard output");
+            }
+            if page_size.is_some() && graph_type != "flat" {
+                bail!("only flat plots can be paginated");
+            }
+            if format == "pdf" && graph_type == "html" {
+                bail!("HTML plots can not be written as PDF");
+            }
+            if matches!(format.as_str(), "tsv" | "json") && graph_type != "barcode" {
+                bail!(
+                    "only the duplications of barcode plots can be exported as {}",
+                    format
+                );
+            }
+            let pdf = format == "pdf";
 
             for filename in files.iter() {
                 info!(
@@ -156,80 +655,353 @@ fn main() -> Result<()> {
                     filename.bold().bright_white(),
                     graph_type.bold().yellow()
                 );
-                let mut out_filename =
-                    std::path::PathBuf::from(out.clone().unwrap_or_else(|| filename.to_string()));
-                out_filename.set_file_name(
-                    out_filename
-                        .file_stem()
-                        .with_context(|| {
-                            anyhow!(
-                                "invalid file name: {}",
-                                out_filename.to_str().unwrap().bold().yellow()
-                            )
-                        })?
-                        .to_owned(),
-                );
-                let out_filename = out_filename.to_str().unwrap();
-                let t = newick::one_from_filename(filename)
-                    .context(format!("failed to read `{}`", filename))?;
-                let out = match graph_type.as_str() {
-                    "flat" => {
-                        let genes = make_genes_cache(&t, &database, &id_column)?;
-                        let colormap = if colorize_per_duplication {
-                            make_colormap_per_duplication(&t, &genes, colorize_all)
-                        } else {
-                            make_colormap(&t, &genes)
-                        };
+                let out_filename = if to_stdout(filename) {
+                    None
+                } else {
+                    Some(plot_stem(filename, out.as_deref())?)
+                };
+                let mut t = read_tree(filename)?;
+                let out = if matches!(graph_type.as_str(), "flat" | "html") {
+                    let placeholders =
+                        db::apply_missing_policy(&mut t, &database, &id_column, &ids, missing)?;
+                    let genes = make_genes_cache_with_placeholders(
+                        &t,
+                        &database,
+                        &id_column,
+                        &ids,
+                        &placeholders,
+                    )?;
+                    infer_species(
+                        &mut t,
+                        Some(&genes),
+                        leaf_species_pattern.as_ref(),
+                        species_tree.as_ref(),
+                    );
+                    let colormap = if colorize_per_duplication {
+                        make_colormap_per_duplication(&t, &genes, colorize_all)
+                    } else {
+                        make_colormap(&t, &genes)
+                    };
+                    let neighbours =
+                        db::make_neighbours_cache(&db::connect(&database)?, &id_column, &genes)?;
+                    if graph_type == "flat" {
                         let petmap = make_petnamemap(&t, &genes);
-                        let out = format!("{}-flat.svg", out_filename);
-                        render::flat::render(
+                        if show_misplaced {
+                            render_settings.highlighted_leaves =
+                                placement::find_misplaced(&t, &genes, misplaced_margin)
+                                    .into_iter()
+                                    .filter_map(|m| t.name(m.leaf).cloned())
+                                    .collect();
+                        }
+                        if let Some(page_size) = page_size {
+                            // Paginated plots are never written to the standard output
+                            render::flat::write_pages(
+                                &t,
+                                &genes,
+                                &neighbours,
+                                &colormap,
+                                &petmap,
+                                page_size,
+                                out_filename.as_deref().unwrap(),
+                                pdf,
+                                &render_settings,
+                            )?
+                            .into_iter()
+                            .next()
+                        } else {
+                            let out = out_filename
+                                .as_ref()
+                                .map(|f| format!("{}-flat.{}", f, format));
+                            render::write_plot(out.as_deref(), pdf, |w| {
+                                render::flat::render(
+                                    &t,
+                                    &genes,
+                                    &neighbours,
+                                    &colormap,
+                                    &petmap,
+                                    w,
+                                    &render_settings,
+                                )
+                            })?;
+                            out
+                        }
+                    } else {
+                        let out = out_filename.as_ref().map(|f| format!("{}.html", f));
+                        let mut w = output(out.as_deref())?;
+                        render::html::render(
                             &t,
                             &genes,
+                            &neighbours,
                             &colormap,
-                            &petmap,
-                            &out,
-                            &render_settings,
-                        );
-                        out
-                    }
-                    "html" => {
-                        let genes = make_genes_cache(&t, &database, &id_column)?;
-                        let colormap = if colorize_per_duplication {
-                            make_colormap_per_duplication(&t, &genes, colorize_all)
-                        } else {
-                            make_colormap(&t, &genes)
-                        };
-                        let out = format!("{}.html", out_filename);
-                        render::html::render(&t, &genes, &colormap, &out);
-                        out
-                    }
-                    "barcode" => {
-                        let out = format!("{}-barcode.svg", out_filename);
-                        render::barcode::render(
-                            &t,
-                            species_tree.as_ref().unwrap(),
-                            &out,
-                            filter_species_tree,
+                            species_tree.as_ref(),
+                            out.as_deref().unwrap_or("genominicus"),
+                            &mut w,
                             &render_settings,
-                        );
+                        )?;
+                        w.flush()?;
                         out
                     }
-                    "skeleton" => {
-                        let out = format!("{}-skeleton.svg", out_filename);
-                        render::skeleton::render(&t, &out, &render_settings);
-                        out
+                } else {
+                    complete_species(
+                        &mut t,
+                        &database,
+                        &id_column,
+                        &ids,
+                        leaf_species_pattern.as_ref(),
+                        species_tree.as_ref(),
+                    )?;
+                    match graph_type.as_str() {
+                        "barcode" => {
+                            let out = out_filename
+                                .as_ref()
+                                .map(|f| format!("{}-barcode.{}", f, format));
+                            let species_tree = species_tree
+                                .as_ref()
+                                .context("a species tree is required for barcode plots")?;
+                            if matches!(format.as_str(), "tsv" | "json") {
+                                let mut w = output(out.as_deref())?;
+                                render::barcode::write_duplications(
+                                    &t,
+                                    species_tree,
+                                    &mut w,
+                                    format == "json",
+                                )?;
+                                w.flush()?;
+                            } else {
+                                render::write_plot(out.as_deref(), pdf, |w| {
+                                    render::barcode::render(
+                                        &t,
+                                        species_tree,
+                                        w,
+                                        filter_species_tree,
+                                        &render_settings,
+                                    )
+                                })?;
+                            }
+                            out
+                        }
+                        "skeleton" => {
+                            let out = out_filename
+                                .as_ref()
+                                .map(|f| format!("{}-skeleton.{}", f, format));
+                            render::write_plot(out.as_deref(), pdf, |w| {
+                                render::skeleton::render(&t, w, &render_settings)
+                            })?;
+                            out
+                        }
+                        _ => unimplemented!(),
                     }
-                    _ => unimplemented!(),
                 };
-                if let Some(open_with) = open.as_ref() {
+                if let (Some(open_with), Some(out)) = (open.as_ref(), out.as_ref()) {
                     if let Some(program) = open_with.as_ref() {
-                        open::with(&out, program)?;
+                        open::with(out, program)?;
                     } else {
-                        open::that(&out)?;
+                        open::that(out)?;
                     };
                 };
             }
             Ok(())
         }
+        Commands::Aggregate {
+            files,
+            species_tree,
+            leaf_species_pattern,
+            out,
+            format,
+            theme,
+        } => {
+            let species_tree = newick::one_from_filename(&species_tree)
+                .with_context(|| anyhow!("failed to read `{}`", species_tree))?;
+            let leaf_species_pattern = leaf_species_pattern
+                .as_deref()
+                .map(genominicus::utils::leaf_species_pattern)
+                .transpose()?;
+            let render_settings = RenderSettings {
+                dimensions: config.dimensions,
+                theme: render::theme::Theme::from_name_or_file(&theme)?,
+                ..Default::default()
+            };
+
+            let mut aggregate = render::barcode::Aggregate::default();
+            let mut skipped = 0;
+            for filename in files.iter() {
+                info!("Aggregating {}", filename.bold().bright_white());
+                for (i, mut t) in newick::from_filename(filename)
+                    .with_context(|| anyhow!("failed to read `{}`", filename))?
+                    .into_iter()
+                    .enumerate()
+                {
+                    infer_species(
+                        &mut t,
+                        None,
+                        leaf_species_pattern.as_ref(),
+                        Some(&species_tree),
+                    );
+                    if let Err(e) = aggregate.add(&t, &species_tree) {
+                        eprintln!(
+                            "{} skipping tree #{} of {}: {:#}",
+                            "warning:".yellow().bold(),
+                            i + 1,
+                            filename.bold(),
+                            e
+                        );
+                        skipped += 1;
+                    }
+                }
+            }
+            if skipped > 0 {
+                eprintln!(
+                    "{} {} trees skipped for missing or unknown species",
+                    "warning:".yellow().bold(),
+                    skipped
+                );
+            }
+
+            let plot = format!("{}-barcode.{}", out, format);
+            render::write_plot(Some(&plot), format == "pdf", |w| {
+                render::barcode::render_aggregate(&species_tree, &aggregate, w, &render_settings)
+            })?;
+            let table = format!("{}-barcode.tsv", out);
+            let mut w = create(&table)?;
+            aggregate.write_table(&species_tree, &mut w)?;
+            w.flush()?;
+            info!(
+                "{} trees aggregated into {} and {}",
+                aggregate.trees,
+                plot.bold().yellow(),
+                table.bold().yellow()
+            );
+            Ok(())
+        }
+        Commands::Annotate {
+            files,
+            out,
+            database,
+            id_column,
+            leaf_ids,
+        } => {
+            let ids = leaf_ids.mapping()?;
+            for filename in files.iter() {
+                info!("Annotating {}", filename.bold().bright_white());
+                let out_filename = if let Some(out) = out.as_ref() {
+                    out.to_owned()
+                } else {
+                    let path = std::path::Path::new(filename);
+                    path.with_file_name(format!(
+                        "{}-annotated.nhx",
+                        path.file_stem()
+                            .and_then(|s| s.to_str())
+                            .with_context(|| anyhow!(
+                                "invalid file name: {}",
+                                filename.bold().yellow()
+                            ))?
+                    ))
+                    .to_str()
+                    .unwrap()
+                    .to_owned()
+                };
+                let mut t = newick::one_from_filename(filename)
+                    .context(format!("failed to read `{}`", filename))?;
+                db::apply_missing_policy(&mut t, &database, &id_column, &ids, MissingPolicy::Fail)?;
+                let (genes, flipped) = make_oriented_genes_cache(&t, &database, &id_column, &ids)?;
+                annotate::annotate(&mut t, &genes, &flipped);
+                std::fs::write(&out_filename, Newick::to_newick(&t, false) + "\n")
+                    .with_context(|| anyhow!("failed to write {}", out_filename.bold().yellow()))?;
+            }
+            Ok(())
+        }
+        Commands::CheckPlacement {
+            files,
+            out,
+            database,
+            id_column,
+            leaf_ids,
+            margin,
+        } => {
+            let ids = leaf_ids.mapping()?;
+            let mut report = String::from("tree\tleaf\tsister_score\tbest_score\tbest_clade\n");
+            for filename in files.iter() {
+                info!("Checking {}", filename.bold().bright_white());
+                let mut t = newick::one_from_filename(filename)
+                    .context(format!("failed to read `{}`", filename))?;
+                db::apply_missing_policy(&mut t, &database, &id_column, &ids, MissingPolicy::Fail)?;
+                let genes = make_genes_cache(&t, &database, &id_column, &ids)?;
+                let misplaced = placement::find_misplaced(&t, &genes, margin);
+                if misplaced.is_empty() {
+                    info!("No misplaced leaf found");
+                }
+                for m in misplaced {
+                    report.push_str(&format!(
+                        "{}\t{}\t{:.3}\t{:.3}\t{}\n",
+                        filename,
+                        t.name(m.leaf).unwrap(),
+                        m.sister_score,
+                        m.best_score,
+                        clade_name(&t, m.best_clade)
+                    ));
+                }
+            }
+            if let Some(out) = out {
+                std::fs::write(&out, report)
+                    .with_context(|| anyhow!("failed to write {}", out.bold().yellow()))?;
+            } else {
+                print!("{}", report);
+            }
+            Ok(())
+        }
+        Commands::Inspect {
+            database,
+            genome_files,
+            species_pattern,
+        } => db::inspect(&database, &genome_files, &species_pattern),
+        Commands::Query {
+            ids,
+            database,
+            id_column,
+            json,
+        } => {
+            let window = if window_is_set {
+                args.window
+            } else {
+                db::window(&database)?
+            };
+            db::query(&database, &id_column, &ids, window, json)
+        }
+        Commands::Serve {
+            trees,
+            database,
+            id_column,
+            leaf_ids,
+            missing,
+            species_tree,
+            port,
+            link_templates,
+            open,
+        } => {
+            let species_tree = species_tree
+                .as_ref()
+                .map(|species_tree| {
+                    newick::one_from_filename(species_tree)
+                        .with_context(|| anyhow!("failed to read `{}`", species_tree))
+                })
+                .transpose()?;
+            let mut render_settings = RenderSettings::default();
+            if let Some(link_templates) = link_templates {
+                render_settings.link_templates = LinkTemplates::from_file(&link_templates)?;
+            }
+            let server = serve::Server::new(
+                &database,
+                &trees,
+                &id_column,
+                leaf_ids.mapping()?,
+                MissingPolicy::try_from(missing.as_str())?,
+                species_tree,
+                render_settings,
+            )?;
+            if open {
+                open::that(format!("http://localhost:{}/", port))?;
+            }
+            server.run(port)
+        }
     }
 }
@@ -0,0 +1,210 @@
+use crate::utils::*;
+use newick::*;
+use std::collections::{HashMap, HashSet};
+use syntesuite::genebook::FamilyID;
+
+/// How much better another clade must fit a leaf for it to be considered misplaced
+pub const DEFAULT_MARGIN: f32 = 0.1;
+
+/// A leaf whose syntenic context is closer to another clade than to its sister
+/// clade
+pub struct Misplacement {
+    /// The misplaced leaf
+    pub leaf: usize,
+    /// Best similarity between the leaf and the consensus landscapes of its sister clade
+    pub sister_score: f32,
+    /// The clade whose consensus landscape is the most similar to the leaf one
+    pub best_clade: usize,
+    /// Similarity between the leaf and the consensus landscape of `best_clade`
+    pub best_score: f32,
+}
+
+// The consensus landscape of each clade of `tree`, i.e. the families present
+// in the landscapes of at least half its leaves
+fn consensuses(tree: &NewickTree, genes: &GeneCache) -> HashMap<usize, HashSet<FamilyID>> {
+    // The number of landscapes under each pending node, and of the ones
+    // featuring each family; children are visited before their parent
+    let mut counts = HashMap::<usize, (usize, HashMap<FamilyID, usize>)>::new();
+    let mut r = HashMap::new();
+    let root = tree.root();
+    for n in std::iter::once(root)
+        .chain(tree.descendants(root))
+        .collect::<Vec<_>>()
+        .into_iter()
+        .rev()
+    {
+        let (landscapes, families) = if tree[n].is_leaf() {
+            landscape_set(genes, tree, n)
+                .map(|l| (1, l.into_iter().map(|f| (f, 1)).collect()))
+                .unwrap_or_default()
+        } else {
+            let mut children = tree[n]
+                .children()
+                .iter()
+                .map(|c| counts.remove(c).unwrap())
+                .collect::<Vec<_>>();
+            // Merge the smaller counts into the largest one
+            children.sort_by_key(|(_, families)| std::cmp::Reverse(families.len()));
+            let mut children = children.into_iter();
+            let (mut landscapes, mut families) = children.next().unwrap_or_default();
+            for (l, fs) in children {
+                landscapes += l;
+                for (f, c) in fs {
+                    *families.entry(f).or_default() += c;
+                }
+            }
+            (landscapes, families)
+        };
+        r.insert(
+            n,
+            families
+                .iter()
+                .filter(|(_, c)| 2 * *c >= landscapes)
+                .map(|(f, _)| *f)
+                .collect(),
+        );
+        counts.insert(n, (landscapes, families));
+    }
+    r
+}
+
+/// Find the leaves of `tree` whose landscape is closer by more than `margin`
+/// to the consensus landscape of another clade than to the ones of their
+/// sister clade and its sub-clades.
+pub fn find_misplaced(tree: &NewickTree, genes: &GeneCache, margin: f32) -> Vec<Misplacement> {
+    let consensuses = consensuses(tree, genes);
+
+    let mut r = Vec::new();
+    for leaf in tree.leaves() {
+        let Some(me) = landscape_set(genes, tree, leaf) else {
+            continue;
+        };
+        let Some(parent) = tree.parent(leaf) else {
+            continue;
+        };
+        let ancestors = tree.ascendance(leaf).into_iter().collect::<HashSet<_>>();
+        // The sister clade and its sub-clades are not considered as alternatives;
+        // and a leaf fitting any of them is not misplaced
+        let sisters = tree
+            .descendants(parent)
+            .into_iter()
+            .filter(|n| *n != leaf)
+            .collect::<HashSet<_>>();
+        let Some(sister_score) = sisters
+            .iter()
+            .filter(|n| !consensuses[n].is_empty())
+            .map(|n| jaccard(&me, &consensuses[n]))
+            .max_by(f32::total_cmp)
+        else {
+            continue;
+        };
+
+        if let Some((best_clade, best_score)) = tree
+            .nodes()
+            .filter(|n| *n != leaf && !ancestors.contains(n) && !sisters.contains(n))
+            .filter(|n| !consensuses[n].is_empty())
+            .map(|n| (n, jaccard(&me, &consensuses[&n])))
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+        {
+            if best_score > sister_score + margin {
+                r.push(Misplacement {
+                    leaf,
+                    sister_score,
+                    best_clade,
+                    best_score,
+                });
+            }
+        }
+    }
+    r
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use syntesuite::genebook::{Gene, TailGene};
+    use syntesuite::Strand;
+
+    fn genes(landscapes: &[(&str, &[FamilyID])]) -> GeneCache {
+        landscapes
+            .iter()
+            .map(|(name, families)| {
+                let gene = Gene {
+                    id: name.to_string(),
+                    species: String::new(),
+                    family: 0,
+                    chr: String::new(),
+                    pos: 0,
+                    strand: Strand::Direct,
+                    left_landscape: families
+                        .iter()
+                        .map(|&family| TailGene {
+                            family,
+                            strand: Strand::Direct,
+                        })
+                        .collect(),
+                    right_landscape: Vec::new(),
+                };
+                (name.to_string(), gene)
+            })
+            .collect()
+    }
+
+    fn misplaced(tree: &NewickTree, genes: &GeneCache, margin: f32) -> Vec<String> {
+        find_misplaced(tree, genes, margin)
+            .into_iter()
+            .map(|m| tree.name(m.leaf).unwrap().to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn consensus() {
+        let tree = newick::one_from_string("((a,b,c)X,d)R;").unwrap();
+        let genes = genes(&[("a", &[1, 2]), ("b", &[1, 3]), ("c", &[1, 2, 4])]);
+        let consensuses = consensuses(&tree, &genes);
+        let clade = |name: &str| {
+            let n = tree.find_node(|n| n.name.as_deref() == Some(name)).unwrap();
+            let mut families = consensuses[&n].iter().copied().collect::<Vec<_>>();
+            families.sort();
+            families
+        };
+        assert_eq!(clade("a"), [1, 2]);
+        assert_eq!(clade("X"), [1, 2]);
+        // Leaves without a landscape are not counted
+        assert_eq!(clade("R"), [1, 2]);
+        assert!(clade("d").is_empty());
+    }
+
+    #[test]
+    fn sibling_clade() {
+        let tree = newick::one_from_string("((a,b)X,(c,d)Y)R;").unwrap();
+        let genes = genes(&[
+            ("a", &[1, 2, 3]),
+            ("b", &[7, 8, 9]),
+            ("c", &[7, 8, 9]),
+            ("d", &[7, 8, 9]),
+        ]);
+        let misplaced = find_misplaced(&tree, &genes, DEFAULT_MARGIN);
+        assert_eq!(misplaced.len(), 1);
+        let m = &misplaced[0];
+        assert_eq!(tree.name(m.leaf).unwrap(), "b");
+        assert_eq!(m.sister_score, 0.);
+        assert_eq!(m.best_score, 1.);
+        // Y and its leaves share the same consensus
+        assert!(["Y", "c", "d"].contains(&tree.name(m.best_clade).unwrap().as_str()));
+    }
+
+    #[test]
+    fn near_tie() {
+        let tree = newick::one_from_string("((a,b)X,(c,d)Y)R;").unwrap();
+        // b is slightly closer to Y (4/11) than to a (3/11)
+        let genes = genes(&[
+            ("a", &[1, 2, 3]),
+            ("b", &[1, 2, 3, 4, 5, 7, 8, 9, 10, 11, 12]),
+            ("c", &[7, 8, 9, 10]),
+            ("d", &[7, 8, 9, 10]),
+        ]);
+        assert_eq!(misplaced(&tree, &genes, 0.), ["b"]);
+        assert!(misplaced(&tree, &genes, DEFAULT_MARGIN).is_empty());
+    }
+}
@@ -0,0 +1,166 @@
+//! Python bindings, enabled by the `python` feature and built with
+//! `maturin build`, e.g. to display plots inline in notebooks:
+//!
+//! ```python
+//! import genominicus
+//! from IPython.display import HTML, SVG
+//!
+//! tree = open("tree.nhx").read()
+//! genes = genominicus.GeneCache("genomes.db", tree)
+//! SVG(genominicus.flat_svg(tree, genes))
+//! HTML(genominicus.html(tree, genes))
+//! ```
+use anyhow::*;
+use newick::NewickTree;
+use pyo3::prelude::*;
+
+use crate::db::{self, NeighbourCache};
+use crate::render::{self, theme::Theme};
+use crate::utils::*;
+
+fn parse_tree(tree: &str) -> Result<NewickTree> {
+    newick::one_from_string(tree).context("failed to parse Newick tree")
+}
+
+fn settings(theme: Option<&str>) -> Result<RenderSettings> {
+    Ok(RenderSettings {
+        theme: theme
+            .map(Theme::from_name_or_file)
+            .transpose()?
+            .unwrap_or_default(),
+        ..Default::default()
+    })
+}
+
+fn to_string(out: Vec<u8>) -> Result<String> {
+    String::from_utf8(out).context("renderer produced invalid UTF-8")
+}
+
+/// The landscapes of the leaves of a gene tree, as read from a database
+#[pyclass(name = "GeneCache")]
+pub struct PyGeneCache {
+    genes: GeneCache,
+    neighbours: NeighbourCache,
+}
+
+#[pymethods]
+impl PyGeneCache {
+    #[new]
+    #[pyo3(signature = (database, tree, id_column = "id", id_pattern = None, id_table = None))]
+    fn new(
+        database: &str,
+        tree: &str,
+        id_column: &str,
+        id_pattern: Option<&str>,
+        id_table: Option<&str>,
+    ) -> Result<Self> {
+        let t = parse_tree(tree)?;
+        let genes = make_genes_cache(
+            &t,
+            database,
+            id_column,
+            &IdMapping::new(id_pattern, id_table)?,
+        )?;
+        let neighbours = db::make_neighbours_cache(&db::connect(database)?, id_column, &genes)?;
+        Ok(PyGeneCache { genes, neighbours })
+    }
+
+    fn __len__(&self) -> usize {
+        self.genes.len()
+    }
+
+    fn __contains__(&self, leaf: &str) -> bool {
+        self.genes.contains_key(leaf)
+    }
+}
+
+/// Render `tree` and the landscapes of its leaves as an SVG string; `theme`
+/// is either `light`, `dark`, `print` or a theme file
+#[pyfunction]
+#[pyo3(signature = (tree, genes, theme = None))]
+fn flat_svg(tree: &str, genes: &PyGeneCache, theme: Option<&str>) -> Result<String> {
+    let t = parse_tree(tree)?;
+    let colormap = make_colormap(&t, &genes.genes);
+    let petmap = make_petnamemap(&t, &genes.genes);
+    let mut out = Vec::new();
+    render::flat::render(
+        &t,
+        &genes.genes,
+        &genes.neighbours,
+        &colormap,
+        &petmap,
+        &mut out,
+        &settings(theme)?,
+    )?;
+    to_string(out)
+}
+
+/// Render the duplications of `tree` along `species_tree` as an SVG string;
+/// the species of the leaves lacking an `S` attribute are looked up in
+/// `genes`, or extracted by `leaf_species_pattern`
+#[pyfunction]
+#[pyo3(signature = (tree, species_tree, filter_species_tree = false, theme = None, genes = None, leaf_species_pattern = None))]
+fn barcode_svg(
+    tree: &str,
+    species_tree: &str,
+    filter_species_tree: bool,
+    theme: Option<&str>,
+    genes: Option<&PyGeneCache>,
+    leaf_species_pattern: Option<&str>,
+) -> Result<String> {
+    let mut t = parse_tree(tree)?;
+    let species_tree = parse_tree(species_tree)?;
+    infer_species(
+        &mut t,
+        genes.map(|g| &g.genes),
+        leaf_species_pattern
+            .map(crate::utils::leaf_species_pattern)
+            .transpose()?
+            .as_ref(),
+        Some(&species_tree),
+    );
+    let mut out = Vec::new();
+    render::barcode::render(
+        &t,
+        &species_tree,
+        &mut out,
+        filter_species_tree,
+        &settings(theme)?,
+    )?;
+    to_string(out)
+}
+
+/// Render `tree` and the landscapes of its leaves as a standalone HTML page
+#[pyfunction]
+#[pyo3(signature = (tree, genes, species_tree = None, title = "genominicus"))]
+fn html(
+    tree: &str,
+    genes: &PyGeneCache,
+    species_tree: Option<&str>,
+    title: &str,
+) -> Result<String> {
+    let t = parse_tree(tree)?;
+    let species_tree = species_tree.map(parse_tree).transpose()?;
+    let colormap = make_colormap(&t, &genes.genes);
+    let mut out = Vec::new();
+    render::html::render(
+        &t,
+        &genes.genes,
+        &genes.neighbours,
+        &colormap,
+        species_tree.as_ref(),
+        title,
+        &mut out,
+        &RenderSettings::default(),
+    )?;
+    to_string(out)
+}
+
+#[pymodule]
+fn genominicus(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyGeneCache>()?;
+    m.add_function(wrap_pyfunction!(flat_svg, m)?)?;
+    m.add_function(wrap_pyfunction!(barcode_svg, m)?)?;
+    m.add_function(wrap_pyfunction!(html, m)?)?;
+    PyResult::Ok(())
+}
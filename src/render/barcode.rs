@@ -1,41 +1,38 @@
+use crate::render::theme::{color, write_svg};
 use crate::utils::*;
+use anyhow::*;
 use newick::*;
+use serde::Serialize;
 use std::collections::{HashMap, HashSet};
-use std::fs::File;
-use std::io::prelude::*;
+use std::io::Write;
 use svarog::*;
 
-const K: f32 = FONT_SIZE;
-
-fn draw_stripes(svg: &mut SvgDrawing, n: usize, width: f32) {
+fn draw_stripes(svg: &mut Group, n: usize, width: f32, render: &RenderSettings) {
+    let k = render.dimensions.font_size;
     for i in 0..n {
         if i % 2 != 0 {
             svg.polygon()
-                .from_corners((0., K * i as f32), (width, K * i as f32 + FONT_SIZE))
-                .style(|s| {
-                    s.fill_color(if i % 4 == 1 {
-                        Some(StyleColor::String("#fcf7d9".into()))
-                    } else {
-                        Some(StyleColor::String("#e5f8f9".into()))
-                    })
-                });
+                .from_corners((0., k * i as f32), (width, k * i as f32 + k))
+                .style(|s| s.fill_color(Some(color(&render.theme.stripes[i / 2 % 2]))));
         }
     }
 }
 
 fn draw_nodes_in_tree(
-    svg: &mut SvgDrawing,
+    svg: &mut Group,
     nodes: &HashMap<String, Vec<f32>>,
     species_map: &HashMap<String, (f32, f32)>,
+    render: &RenderSettings,
 ) {
+    let k = render.dimensions.font_size;
     for mrca in nodes.keys() {
         let dups = &nodes[mrca];
         let opacity = 1. / dups.len() as f32;
         let (mut x, mut y) = species_map.get(mrca).unwrap();
         for dcs in dups {
-            let c = StyleColor::Percent(1. - dcs, *dcs, 0.);
+            let c = render.theme.score(*dcs);
             svg.polygon()
-                .from_pos_dims(x - 3., y - 3. + K / 2., 6., 6.)
+                .from_pos_dims(x - 3., y - 3. + k / 2., 6., 6.)
                 .style(|s| s.fill_color(Some(c)).fill_opacity(opacity));
             x += 1.;
             y += 1.;
@@ -48,7 +45,9 @@ fn draw_species_tree(
     species_tree: &NewickTree,
     species_to_render: &[&String],
     present_species: &[&String],
+    render: &RenderSettings,
 ) -> (Group, HashMap<String, (f32, f32)>) {
+    #[allow(clippy::too_many_arguments)]
     fn render_node(
         svg: &mut Group,
         x: f32,
@@ -59,29 +58,31 @@ fn draw_species_tree(
         species_to_render: &[&String],
         present_species: &[&String],
         species_map: &mut HashMap<String, (f32, f32)>,
+        render: &RenderSettings,
     ) -> f32 {
+        let k = render.dimensions.font_size;
+        let theme = &render.theme;
         let mut y = y;
         if t[n].is_leaf() {
             t.name(n).map(|name| {
                 svg.line()
-                    .from_coords(x, y + K, xlabels, y + K)
-                    .style(|s| s.stroke_color(StyleColor::RGB(0, 0, 0)).stroke_width(0.5))
-                    .shift(0., -K / 2.);
-                svg.text()
-                    .pos(xlabels + K, y + FONT_SIZE)
-                    .text(name)
+                    .from_coords(x, y + k, xlabels, y + k)
                     .style(|s| {
-                        s.fill_color(Some(name2color(name))).fill_opacity(
-                            if present_species.contains(&name) {
-                                1.0
-                            } else {
-                                0.3
-                            },
-                        )
-                    });
+                        s.stroke_color(color(&theme.foreground))
+                            .stroke_width(theme.stroke_width)
+                    })
+                    .shift(0., -k / 2.);
+                svg.text().pos(xlabels + k, y + k).text(name).style(|s| {
+                    s.fill_color(Some(theme.paint(name2color(name))))
+                        .fill_opacity(if present_species.contains(&name) {
+                            1.0
+                        } else {
+                            0.3
+                        })
+                });
                 species_map.insert(name.to_string(), (xlabels, y))
             });
-            y += K;
+            y += k;
         } else {
             let base_y = y;
 
@@ -96,22 +97,31 @@ fn draw_species_tree(
                 }) {
                     if i == 0 {
                         svg.line()
-                            .from_coords(x, y + K, x + K, y + K)
-                            .style(|s| s.stroke_color(StyleColor::RGB(0, 0, 0)).stroke_width(0.5))
-                            .shift(0., -K / 2.);
+                            .from_coords(x, y + k, x + k, y + k)
+                            .style(|s| {
+                                s.stroke_color(color(&theme.foreground))
+                                    .stroke_width(theme.stroke_width)
+                            })
+                            .shift(0., -k / 2.);
                     } else {
                         svg.line()
-                            .from_coords(x, base_y + K, x, y + K)
-                            .style(|s| s.stroke_color(StyleColor::RGB(0, 0, 0)).stroke_width(0.5))
-                            .shift(0., -K / 2.);
+                            .from_coords(x, base_y + k, x, y + k)
+                            .style(|s| {
+                                s.stroke_color(color(&theme.foreground))
+                                    .stroke_width(theme.stroke_width)
+                            })
+                            .shift(0., -k / 2.);
                         svg.line()
-                            .from_coords(x, y + K, x + K, y + K)
-                            .style(|s| s.stroke_color(StyleColor::RGB(0, 0, 0)).stroke_width(0.5))
-                            .shift(0., -K / 2.);
+                            .from_coords(x, y + k, x + k, y + k)
+                            .style(|s| {
+                                s.stroke_color(color(&theme.foreground))
+                                    .stroke_width(theme.stroke_width)
+                            })
+                            .shift(0., -k / 2.);
                     }
                     y = render_node(
                         svg,
-                        x + K,
+                        x + k,
                         xlabels,
                         y,
                         t,
@@ -119,6 +129,7 @@ fn draw_species_tree(
                         species_to_render,
                         present_species,
                         species_map,
+                        render,
                     );
                 }
             }
@@ -126,39 +137,67 @@ fn draw_species_tree(
         y
     }
 
+    let k = render.dimensions.font_size;
     let mut species_map = HashMap::<String, (f32, f32)>::new();
     let mut out = Group::new();
     render_node(
         &mut out,
         0.,
-        species_tree.topological_depth().1 as f32 * K,
+        species_tree.topological_depth().1 as f32 * k,
         0.,
         species_tree,
         species_tree.root(),
         species_to_render,
         present_species,
         &mut species_map,
+        render,
     );
     (out, species_map)
 }
 
-pub fn draw_duplications_blocks(
-    t: &NewickTree,
-    species_tree: &NewickTree,
-    species_map: &mut HashMap<String, (f32, f32)>,
-    render: &RenderSettings,
-) -> (Group, HashMap<String, Vec<f32>>) {
-    fn species_name(t: &NewickTree, n: usize) -> String {
-        t.attrs(n)
-            .get("S")
-            .expect("no species (`S`) annotation found in tree")
-            .to_string()
+/// A duplication of a gene tree, mapped onto the species tree
+#[derive(Debug, Clone)]
+pub struct Duplication {
+    /// The duplication node in the gene tree
+    pub node: usize,
+    /// The species found in each arm of the duplication
+    pub arms: Vec<HashSet<String>>,
+    /// The duplication consistency score, if the node is annotated with one
+    pub dcs: Option<f32>,
+    /// The MRCA in the species tree of the species under the duplication
+    pub mrca: usize,
+}
+impl Duplication {
+    /// The fraction of the species descending from the MRCA where at least two
+    /// arms of the duplication were retained
+    pub fn retention(&self, species_tree: &NewickTree) -> f32 {
+        let retained = self
+            .arms
+            .iter()
+            .flatten()
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .filter(|s| self.arms.iter().filter(|a| a.contains(*s)).count() > 1)
+            .count();
+        retained as f32 / species_tree.leaves_of(self.mrca).len() as f32
     }
+}
 
-    let mut out = Group::new();
-    let mut xoffset = 0.;
-    // ([Arm{}], DCS, MRCA ID, DupID)
-    let mut duplication_sets: Vec<(Vec<(HashSet<String>, i32, i32)>, f32, usize, usize)> = t
+/// The species (`S`) annotation of the leaf `n`, see
+/// [`infer_species`](crate::utils::infer_species) to fill in the missing ones
+fn species_name(t: &NewickTree, n: usize) -> Result<&str> {
+    t.attrs(n).get("S").map(String::as_str).with_context(|| {
+        anyhow!(
+            "no species (`S`) annotation found for leaf {}",
+            t.name(n).map(String::as_str).unwrap_or("(unnamed)")
+        )
+    })
+}
+
+/// The duplications of `t` mapped onto `species_tree`, the most ancient ones
+/// first
+pub fn duplications(t: &NewickTree, species_tree: &NewickTree) -> Result<Vec<Duplication>> {
+    let mut duplications = t
         .inners()
         .filter(|&n| t.is_duplication(n))
         .map(|n| {
@@ -166,120 +205,193 @@ pub fn draw_duplications_blocks(
                 .children()
                 .iter()
                 .map(|&c| {
-                    (
-                        t.leaves_of(c)
-                            .iter()
-                            .map(|&n| species_name(t, n))
-                            .collect::<HashSet<_>>(),
-                        0,
-                        0,
-                    )
+                    t.leaves_of(c)
+                        .iter()
+                        .map(|&n| species_name(t, n).map(str::to_owned))
+                        .collect::<Result<HashSet<_>>>()
                 })
-                .collect::<Vec<_>>();
-            let all_species = t
-                .leaves_of(n)
+                .collect::<Result<Vec<_>>>()?;
+            let all_species = arms
                 .iter()
-                .map(|o| {
+                .flatten()
+                .map(|species| {
                     species_tree
-                        .find_leaf(|n| {
-                            n.name
-                                .as_ref()
-                                .map(|n| *n == species_name(t, *o))
-                                .unwrap_or(false)
-                        })
-                        .unwrap_or_else(|| {
-                            panic!("{} not found in species tree", species_name(t, *o))
-                        })
+                        .find_leaf(|n| n.name.as_ref() == Some(species))
+                        .with_context(|| anyhow!("{} not found in species tree", species))
                 })
-                .collect::<Vec<_>>();
+                .collect::<Result<Vec<_>>>()?;
 
-            let mrca = species_tree.mrca(all_species).unwrap();
-            (
+            Ok(Duplication {
+                node: n,
                 arms,
-                t.attrs(n)
+                dcs: t
+                    .attrs(n)
                     .get("DCS")
-                    .and_then(|dcs| dcs.parse::<f32>().ok())
-                    .unwrap_or_default(),
-                mrca,
-                n,
-            )
+                    .and_then(|dcs| dcs.parse::<f32>().ok()),
+                mrca: species_tree.mrca(all_species).unwrap(),
+            })
         })
-        .collect::<Vec<_>>();
-    duplication_sets.sort_by_cached_key(|a| {
+        .collect::<Result<Vec<_>>>()?;
+    duplications.sort_by_cached_key(|d| {
         (
-            -(species_tree.leaves_of(a.2).len() as i64),
-            -(a.0.iter().map(|x| x.0.len()).sum::<usize>() as i64),
+            -(species_tree.leaves_of(d.mrca).len() as i64),
+            -(d.arms.iter().map(|a| a.len()).sum::<usize>() as i64),
         )
     });
+    Ok(duplications)
+}
+
+/// A [`Duplication`], as exported by [`write_duplications`]
+#[derive(Serialize)]
+struct DuplicationRecord {
+    node: usize,
+    name: Option<String>,
+    dcs: Option<f32>,
+    mrca: String,
+    arms: Vec<Vec<String>>,
+}
 
+/// Write the duplications of `t` mapped onto `species_tree` to `out`, either
+/// as TSV or as JSON; duplications are identified by the pre-order rank of
+/// their node in `t`, the root being 0, and by its name if it has one. In TSV,
+/// missing values are left empty, the species of each arm are separated by
+/// commas, and the arms by `|`
+pub fn write_duplications(
+    t: &NewickTree,
+    species_tree: &NewickTree,
+    out: &mut impl Write,
+    json: bool,
+) -> Result<()> {
+    let ranks = std::iter::once(t.root())
+        .chain(t.descendants(t.root()))
+        .enumerate()
+        .map(|(rank, n)| (n, rank))
+        .collect::<HashMap<_, _>>();
+    let records = duplications(t, species_tree)?
+        .into_iter()
+        .map(|d| DuplicationRecord {
+            node: ranks[&d.node],
+            name: t.name(d.node).cloned(),
+            dcs: d.dcs,
+            mrca: clade_name(species_tree, d.mrca),
+            arms: d
+                .arms
+                .into_iter()
+                .map(|arm| {
+                    let mut arm = arm.into_iter().collect::<Vec<_>>();
+                    arm.sort();
+                    arm
+                })
+                .collect(),
+        })
+        .collect::<Vec<_>>();
+
+    if json {
+        serde_json::to_writer_pretty(&mut *out, &records)?;
+        writeln!(out)?;
+    } else {
+        writeln!(out, "node\tname\tdcs\tmrca\tarms")?;
+        for r in records {
+            writeln!(
+                out,
+                "{}\t{}\t{}\t{}\t{}",
+                r.node,
+                r.name.unwrap_or_default(),
+                r.dcs.map(|dcs| format!("{:.3}", dcs)).unwrap_or_default(),
+                r.mrca,
+                r.arms
+                    .iter()
+                    .map(|arm| arm.join(","))
+                    .collect::<Vec<_>>()
+                    .join("|")
+            )?;
+        }
+    }
+    Ok(())
+}
+
+pub fn draw_duplications_blocks(
+    t: &NewickTree,
+    duplications: &[Duplication],
+    species_tree: &NewickTree,
+    species_map: &mut HashMap<String, (f32, f32)>,
+    render: &RenderSettings,
+) -> Result<(Group, HashMap<String, Vec<f32>>)> {
+    let k = render.dimensions.font_size;
+    let mut out = Group::new();
+    let mut xoffset = 0.;
     let mut dup_nodes: HashMap<String, Vec<f32>> = HashMap::new();
-    for d in duplication_sets.iter() {
-        let dcs = d.1;
-        let c = StyleColor::Percent(1. - dcs, dcs, 0.);
-        // let elc_all = d.3;
-        // let elc_large = d.4;
-        let mrca = d.2;
-        let mrca_name = species_tree.name(mrca).unwrap();
+    for d in duplications.iter() {
+        let dcs = d.dcs.unwrap_or_default();
+        let c = render.theme.score(dcs);
+        let mrca = d.mrca;
+        let mrca_name = species_tree
+            .name(mrca)
+            .context("the species tree has unnamed inner nodes")?;
         dup_nodes
             .entry(mrca_name.to_string())
             .or_default()
             .push(dcs);
 
-        let y_min =
-            d.0.iter()
-                .flat_map(|a| a.0.iter().map(|s| species_map.get(s).unwrap().1))
-                .fold(f32::INFINITY, f32::min);
-        let y_max =
-            d.0.iter()
-                .flat_map(|a| a.0.iter().map(|s| species_map.get(s).unwrap().1))
-                .fold(f32::NEG_INFINITY, f32::max);
+        let y_min = d
+            .arms
+            .iter()
+            .flat_map(|a| a.iter().map(|s| species_map.get(s).unwrap().1))
+            .fold(f32::INFINITY, f32::min);
+        let y_max = d
+            .arms
+            .iter()
+            .flat_map(|a| a.iter().map(|s| species_map.get(s).unwrap().1))
+            .fold(f32::NEG_INFINITY, f32::max);
 
         out.polygon()
             .from_corners(
                 (xoffset, y_min),
-                (xoffset + d.0.len() as f32 * K, y_max + K),
+                (xoffset + d.arms.len() as f32 * k, y_max + k),
             )
             .style(|s| {
                 s.fill_color(Some(c.clone()));
                 s.fill_opacity(0.3)
             });
 
-        for (shift, arm) in d.0.iter().enumerate() {
-            for species in arm.0.iter() {
+        for (shift, arm) in d.arms.iter().enumerate() {
+            for species in arm.iter() {
                 let y = species_map.get(species).unwrap().1;
                 out.polygon()
-                    .from_pos_dims(xoffset + shift as f32 * K, y, K, K)
+                    .from_pos_dims(xoffset + shift as f32 * k, y, k, k)
                     .style(|s| s.fill_color(Some(c.clone())));
             }
         }
 
         for (label_offset, annotation) in render.node_annotations.iter().enumerate() {
             let label_offset = label_offset as f32;
-            if let Some(annotation) = t.attrs(d.3).get(annotation) {
+            if let Some(annotation) = t.attrs(d.node).get(annotation) {
                 out.text()
-                    .pos(xoffset + 1.1 * label_offset + K, y_min)
+                    .pos(xoffset + 1.1 * label_offset + k, y_min)
                     .text(annotation);
             }
         }
 
-        xoffset += d.0.len() as f32 * K + 10.;
+        xoffset += d.arms.len() as f32 * k + 10.;
     }
-    (out, dup_nodes)
+    Ok((out, dup_nodes))
 }
 
+/// Render the duplications of `t` along `species_tree` as an SVG written to
+/// `out`; if `filter_species_tree` is set, the species absent from `t` are
+/// left out.
 pub fn render(
     t: &NewickTree,
-    species_tree_filename: &str,
-    out_filename: &str,
+    species_tree: &NewickTree,
+    out: &mut impl Write,
     filter_species_tree: bool,
     render: &RenderSettings,
-) {
-    let mut svg = SvgDrawing::new();
-    let species_tree = newick::one_from_filename(species_tree_filename).unwrap();
+) -> Result<()> {
+    let mut svg = Group::new();
     let species_in_tree = t
         .leaves()
-        .map(|s| t.attrs(s)["S"].as_str())
-        .collect::<HashSet<&str>>();
+        .map(|l| species_name(t, l))
+        .collect::<Result<HashSet<&str>>>()?;
     let species_to_render = species_tree
         .leaf_names()
         .filter(|s| !filter_species_tree || species_in_tree.contains(s.as_str()))
@@ -290,16 +402,396 @@ pub fn render(
         .collect::<Vec<_>>();
 
     let (tree_group, mut present_species_map) =
-        draw_species_tree(&species_tree, &species_to_render, &present_species);
-    let (mut dups_group, dups_nodes) =
-        draw_duplications_blocks(t, &species_tree, &mut present_species_map, render);
+        draw_species_tree(species_tree, &species_to_render, &present_species, render);
+    let duplications = duplications(t, species_tree)?;
+    let (mut dups_group, dups_nodes) = draw_duplications_blocks(
+        t,
+        &duplications,
+        species_tree,
+        &mut present_species_map,
+        render,
+    )?;
     dups_group.shift(tree_group.bbox().x2, 0.);
-    draw_stripes(&mut svg, species_to_render.len(), dups_group.bbox().x2);
-    svg.push(Box::new(tree_group));
-    svg.push(Box::new(dups_group));
-    draw_nodes_in_tree(&mut svg, &dups_nodes, &present_species_map);
-
-    svg.auto_fit();
-    let mut out = File::create(out_filename).unwrap();
-    out.write_all(svg.render_svg().as_bytes()).unwrap();
+    draw_stripes(
+        &mut svg,
+        species_to_render.len(),
+        dups_group.bbox().x2,
+        render,
+    );
+    let mut svg = svg.push(Box::new(tree_group)).push(Box::new(dups_group));
+    draw_nodes_in_tree(&mut svg, &dups_nodes, &present_species_map, render);
+
+    write_svg(svg, &render.theme, out)
+}
+
+/// The duplications of many gene trees whose MRCA is the lower node of a
+/// branch of the species tree
+#[derive(Debug, Clone, Default)]
+pub struct BranchSummary {
+    /// The DCS of each duplication annotated with one
+    pub dcs: Vec<f32>,
+    /// The number of duplications without a DCS
+    pub without_dcs: usize,
+    /// The retention of each duplication, see [`Duplication::retention`]
+    pub retention: Vec<f32>,
+}
+impl BranchSummary {
+    pub fn count(&self) -> usize {
+        self.dcs.len() + self.without_dcs
+    }
+
+    /// The mean DCS of the duplications annotated with one, if any
+    pub fn mean_dcs(&self) -> Option<f32> {
+        (!self.dcs.is_empty()).then(|| mean(&self.dcs))
+    }
+
+    pub fn mean_retention(&self) -> f32 {
+        mean(&self.retention)
+    }
+
+    /// The number of duplications annotated with a DCS in each quarter of the
+    /// DCS range
+    pub fn dcs_histogram(&self) -> [usize; 4] {
+        let mut histogram = [0; 4];
+        for dcs in self.dcs.iter() {
+            histogram[((dcs * 4.) as usize).min(3)] += 1;
+        }
+        histogram
+    }
+}
+
+fn mean(xs: &[f32]) -> f32 {
+    if xs.is_empty() {
+        0.
+    } else {
+        xs.iter().sum::<f32>() / xs.len() as f32
+    }
+}
+
+/// The duplications of many gene trees, summarized along the branches of a
+/// species tree
+#[derive(Debug, Default)]
+pub struct Aggregate {
+    /// The number of aggregated gene trees
+    pub trees: usize,
+    /// The summary of each branch, by the ID of its lower node
+    pub branches: HashMap<usize, BranchSummary>,
+}
+impl Aggregate {
+    /// Add the duplications of `t` to the summary
+    pub fn add(&mut self, t: &NewickTree, species_tree: &NewickTree) -> Result<()> {
+        for d in duplications(t, species_tree)? {
+            let branch = self.branches.entry(d.mrca).or_default();
+            if let Some(dcs) = d.dcs {
+                branch.dcs.push(dcs);
+            } else {
+                branch.without_dcs += 1;
+            }
+            branch.retention.push(d.retention(species_tree));
+        }
+        self.trees += 1;
+        Ok(())
+    }
+
+    /// Write the summary of each branch of `species_tree` as TSV; branches are
+    /// identified by the pre-order rank of their lower node in `species_tree`,
+    /// the root being 0, and by its name if it has one. The mean DCS is left
+    /// empty for branches without any duplication annotated with a DCS.
+    pub fn write_table(&self, species_tree: &NewickTree, out: &mut impl Write) -> Result<()> {
+        writeln!(
+            out,
+            "node\tname\tduplications\tdcs_missing\tdcs_mean\tdcs_0.00-0.25\tdcs_0.25-0.50\tdcs_0.50-0.75\tdcs_0.75-1.00\tretention_mean"
+        )?;
+        let root = species_tree.root();
+        for (rank, n) in std::iter::once(root)
+            .chain(species_tree.descendants(root))
+            .enumerate()
+        {
+            let branch = self.branches.get(&n).cloned().unwrap_or_default();
+            let [q1, q2, q3, q4] = branch.dcs_histogram();
+            writeln!(
+                out,
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{:.3}",
+                rank,
+                species_tree.name(n).map(String::as_str).unwrap_or_default(),
+                branch.count(),
+                branch.without_dcs,
+                branch
+                    .mean_dcs()
+                    .map(|dcs| format!("{:.3}", dcs))
+                    .unwrap_or_default(),
+                q1,
+                q2,
+                q3,
+                q4,
+                branch.mean_retention()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn draw_aggregate_node(
+    svg: &mut Group,
+    t: &NewickTree,
+    n: usize,
+    x: f32,
+    y: &mut f32,
+    xlabels: f32,
+    aggregate: &Aggregate,
+    heat: &dyn Fn(usize) -> f32,
+    render: &RenderSettings,
+) -> f32 {
+    let k = render.dimensions.font_size;
+    let theme = &render.theme;
+    let x_node = x + 3. * k;
+
+    let y_node = if t[n].is_leaf() {
+        let y_node = *y + k;
+        *y += 2. * k;
+        svg.line()
+            .from_coords(x_node, y_node, xlabels, y_node)
+            .style(|s| {
+                s.stroke_color(color(&theme.foreground))
+                    .stroke_width(theme.stroke_width)
+                    .stroke_opacity(0.3)
+            });
+        if let Some(name) = t.name(n) {
+            svg.text()
+                .pos(xlabels + k / 2., y_node + k / 3.)
+                .text(name)
+                .style(|s| s.fill_color(Some(theme.paint(name2color(name)))));
+        }
+        y_node
+    } else {
+        let ys = t[n]
+            .children()
+            .iter()
+            .map(|&c| draw_aggregate_node(svg, t, c, x_node, y, xlabels, aggregate, heat, render))
+            .collect::<Vec<_>>();
+        svg.line()
+            .from_coords(x_node, ys[0], x_node, *ys.last().unwrap())
+            .style(|s| {
+                s.stroke_color(color(&theme.foreground))
+                    .stroke_width(theme.stroke_width)
+            });
+        (ys[0] + ys.last().unwrap()) / 2.
+    };
+
+    let branch = aggregate.branches.get(&n);
+    let count = branch.map(|b| b.count()).unwrap_or(0);
+    svg.line()
+        .from_coords(x, y_node, x_node, y_node)
+        .style(|s| {
+            s.stroke_color(theme.heat(heat(count)))
+                .stroke_width(theme.focus_stroke_width * 2.)
+        });
+    if let Some(branch) = branch {
+        svg.text().pos(x + 2., y_node - 3.).text(count.to_string());
+        svg.polygon()
+            .from_pos_dims(x_node - 3., y_node - 3., 6., 6.)
+            .style(|s| {
+                s.fill_color(Some(
+                    branch
+                        .mean_dcs()
+                        .map(|dcs| theme.score(dcs))
+                        .unwrap_or_else(|| color(&theme.missing)),
+                ))
+            });
+    }
+    y_node
+}
+
+/// Draw a color scale from `f(0)` to `f(1)` at `(x, y)`, labelled with `low`
+/// and `high`
+fn draw_scale(
+    svg: &mut Group,
+    x: f32,
+    y: f32,
+    f: impl Fn(f32) -> StyleColor,
+    low: &str,
+    high: &str,
+    render: &RenderSettings,
+) {
+    let k = render.dimensions.font_size;
+    svg.text().pos(x, y + k).text(low);
+    let x = x + (low.len() as f32 + 1.) * k * 0.6;
+    for i in 0..10 {
+        svg.polygon()
+            .from_pos_dims(x + i as f32 * k, y, k, k)
+            .style(|s| s.fill_color(Some(f(i as f32 / 9.))));
+    }
+    svg.text().pos(x + 10.5 * k, y + k).text(high);
+}
+
+/// Render the duplications summarized in `aggregate` as an SVG written to
+/// `out`; the branches of `species_tree` are colored by their number of
+/// duplications, and their lower node by the mean DCS of these duplications.
+pub fn render_aggregate(
+    species_tree: &NewickTree,
+    aggregate: &Aggregate,
+    out: &mut impl Write,
+    render: &RenderSettings,
+) -> Result<()> {
+    let k = render.dimensions.font_size;
+    let theme = &render.theme;
+    let mut svg = Group::new();
+
+    let max = aggregate
+        .branches
+        .values()
+        .map(|b| b.count())
+        .max()
+        .unwrap_or_default();
+    // Duplication counts are heavily skewed, hence a log scale
+    let heat = |count: usize| {
+        if max == 0 {
+            0.
+        } else {
+            (1. + count as f32).ln() / (1. + max as f32).ln()
+        }
+    };
+
+    svg.text().pos(0., k).text(format!(
+        "{} gene trees, {} duplications",
+        aggregate.trees,
+        aggregate
+            .branches
+            .values()
+            .map(|b| b.count())
+            .sum::<usize>()
+    ));
+    let xlabels = (species_tree.topological_depth().1 as f32 + 1.) * 3. * k;
+    let mut y = 2. * k;
+    draw_aggregate_node(
+        &mut svg,
+        species_tree,
+        species_tree.root(),
+        0.,
+        &mut y,
+        xlabels,
+        aggregate,
+        &heat,
+        render,
+    );
+
+    y += k;
+    draw_scale(
+        &mut svg,
+        0.,
+        y,
+        |f| theme.heat(f),
+        "0",
+        &format!("{} duplications", max),
+        render,
+    );
+    draw_scale(
+        &mut svg,
+        0.,
+        y + 2. * k,
+        |f| theme.score(f),
+        "0",
+        "1 mean DCS",
+        render,
+    );
+
+    write_svg(svg, theme, out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPECIES_TREE: &str = "((hsapiens,mmusculus)Euarchontoglires,drerio)Vertebrata;";
+
+    #[test]
+    fn dcs_histogram() {
+        let branch = BranchSummary {
+            dcs: vec![0., 0.24, 0.25, 0.6, 0.99, 1.],
+            without_dcs: 2,
+            retention: Vec::new(),
+        };
+        assert_eq!(branch.count(), 8);
+        assert_eq!(branch.dcs_histogram(), [2, 1, 1, 2]);
+        assert_eq!(BranchSummary::default().dcs_histogram(), [0; 4]);
+        assert_eq!(BranchSummary::default().mean_dcs(), None);
+    }
+
+    #[test]
+    fn retention() {
+        let species_tree = newick::one_from_string(SPECIES_TREE).unwrap();
+        let t = newick::one_from_string(
+            "((a[&&NHX:S=hsapiens],b[&&NHX:S=mmusculus]),(c[&&NHX:S=hsapiens],d[&&NHX:S=drerio]))[&&NHX:D=Y:DCS=0.5];",
+        )
+        .unwrap();
+        let duplications = duplications(&t, &species_tree).unwrap();
+        assert_eq!(duplications.len(), 1);
+        let d = &duplications[0];
+        assert_eq!(d.dcs, Some(0.5));
+        assert_eq!(species_tree.name(d.mrca).unwrap(), "Vertebrata");
+        // Only hsapiens kept both copies, out of the three vertebrates
+        assert_eq!(d.retention(&species_tree), 1. / 3.);
+    }
+
+    #[test]
+    fn export() {
+        let species_tree = newick::one_from_string(SPECIES_TREE).unwrap();
+        let t = newick::one_from_string(
+            "(z[&&NHX:S=drerio],(a[&&NHX:S=hsapiens],b[&&NHX:S=hsapiens])[&&NHX:D=Y]);",
+        )
+        .unwrap();
+        let mut tsv = Vec::new();
+        write_duplications(&t, &species_tree, &mut tsv, false).unwrap();
+        assert_eq!(
+            String::from_utf8(tsv).unwrap(),
+            "node\tname\tdcs\tmrca\tarms\n2\t\t\thsapiens\thsapiens|hsapiens\n"
+        );
+        let mut json = Vec::new();
+        write_duplications(&t, &species_tree, &mut json, true).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(json[0]["node"], 2);
+        assert!(json[0]["name"].is_null());
+        assert!(json[0]["dcs"].is_null());
+    }
+
+    #[test]
+    fn aggregate_without_dcs() {
+        let species_tree = newick::one_from_string(SPECIES_TREE).unwrap();
+        let vertebrata = species_tree.root();
+        let mut aggregate = Aggregate::default();
+        for t in [
+            "(a[&&NHX:S=hsapiens],b[&&NHX:S=drerio])[&&NHX:D=Y:DCS=0.8];",
+            "(a[&&NHX:S=hsapiens],b[&&NHX:S=drerio])[&&NHX:D=Y];",
+        ] {
+            aggregate
+                .add(&newick::one_from_string(t).unwrap(), &species_tree)
+                .unwrap();
+        }
+        let branch = &aggregate.branches[&vertebrata];
+        assert_eq!(branch.count(), 2);
+        assert_eq!(branch.without_dcs, 1);
+        // The duplication without DCS does not weigh on the DCS statistics
+        assert_eq!(branch.mean_dcs(), Some(0.8));
+        assert_eq!(branch.dcs_histogram(), [0, 0, 0, 1]);
+
+        let mut table = Vec::new();
+        aggregate.write_table(&species_tree, &mut table).unwrap();
+        let table = String::from_utf8(table).unwrap();
+        let rows = table.lines().collect::<Vec<_>>();
+        assert_eq!(rows[1], "0\tVertebrata\t2\t1\t0.800\t0\t0\t0\t1\t0.000");
+        assert_eq!(rows[2], "1\tEuarchontoglires\t0\t0\t\t0\t0\t0\t0\t0.000");
+    }
+
+    #[test]
+    fn unknown_species() {
+        let species_tree = newick::one_from_string(SPECIES_TREE).unwrap();
+        let t = newick::one_from_string("(a[&&NHX:S=hsapiens],b[&&NHX:S=ggallus])[&&NHX:D=Y];")
+            .unwrap();
+        assert!(duplications(&t, &species_tree).is_err());
+        let mut aggregate = Aggregate::default();
+        assert!(aggregate.add(&t, &species_tree).is_err());
+        assert_eq!(aggregate.trees, 0);
+        assert!(aggregate.branches.is_empty());
+    }
 }
@@ -1,6 +1,8 @@
-use std::fs::File;
-use std::io::prelude::*;
+use anyhow::{ensure, Context, Result};
+use std::io::Write;
 
+use crate::db::{Neighbour, NeighbourCache};
+use crate::render::theme::{color, write_svg, Theme};
 use crate::utils::*;
 use newick::*;
 use svarog::*;
@@ -9,43 +11,98 @@ use syntesuite::genebook::Gene;
 use syntesuite::Strand;
 
 const MARGIN_TOP: f32 = 100.0;
+const ROW_HEIGHT: f32 = 20.0;
 
+/// The band of ordinates drawn on the current page; the rows of the leaves
+/// extend on half a row on both sides of their ordinate.
+#[derive(Clone, Copy)]
+struct View {
+    top: f32,
+    bottom: f32,
+}
+impl View {
+    const ALL: View = View {
+        top: f32::NEG_INFINITY,
+        bottom: f32::INFINITY,
+    };
+
+    fn page(page: usize, page_size: usize) -> Self {
+        let height = ROW_HEIGHT * page_size as f32;
+        View {
+            top: MARGIN_TOP + height * page as f32,
+            bottom: MARGIN_TOP + height * (page + 1) as f32,
+        }
+    }
+
+    fn contains(&self, y: f32) -> bool {
+        y >= self.top && y < self.bottom
+    }
+
+    /// The part of the vertical segment `[y1, y2]` lying on the page, if any
+    fn clip(&self, y1: f32, y2: f32) -> Option<(f32, f32)> {
+        let y1 = y1.max(self.top - ROW_HEIGHT / 2.);
+        let y2 = y2.min(self.bottom - ROW_HEIGHT / 2.);
+        (y1 < y2).then_some((y1, y2))
+    }
+
+    /// The (1-based) number of the page containing the row at `y`
+    fn page_of(&self, y: f32) -> usize {
+        ((y - MARGIN_TOP) / (self.bottom - self.top)) as usize + 1
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 fn draw_background(
-    svg: &mut SvgDrawing,
+    svg: &mut Group,
     depth: f32,
     tree: &NewickTree,
     node: usize,
     xoffset: f32,
     yoffset: f32,
     width: f32,
+    view: View,
+    render: &RenderSettings,
 ) -> f32 {
+    let branch_width = render.dimensions.branch_width;
     let mut y = yoffset;
 
     let mut children = tree.children(node).unwrap().to_vec();
     children.sort_by_key(|c| tree.name(*c).cloned().unwrap_or_else(|| "Z".to_string()));
 
     if children.is_empty() {
-        return y + 20.;
+        return y + ROW_HEIGHT;
     }
 
     for &child in children.iter() {
         let new_y = if tree[child].is_leaf() {
-            y + 20.
+            y + ROW_HEIGHT
         } else {
-            draw_background(svg, depth, tree, child, xoffset + BRANCH_WIDTH, y, width)
+            draw_background(
+                svg,
+                depth,
+                tree,
+                child,
+                xoffset + branch_width,
+                y,
+                width,
+                view,
+                render,
+            )
         };
 
-        if tree.is_duplication(node) {
+        if let (true, Some((top, bottom))) =
+            (tree.is_duplication(node), view.clip(y - 6., new_y - 12.))
+        {
             let d = xoffset / depth;
             svg.polygon()
                 .from_pos_dims(
-                    xoffset + BRANCH_WIDTH / 2.,
-                    y - 6.,
-                    width - xoffset - d * BRANCH_WIDTH,
-                    new_y - y - 6.,
+                    xoffset + branch_width / 2.,
+                    top,
+                    width - xoffset - d * branch_width,
+                    bottom - top,
                 )
                 .style(|s| {
-                    s.fill_color(Some(StyleColor::Percent(0.5, 0.5, 1.)))
+                    s.fill_color(Some(color(&render.theme.duplication)))
                         .fill_opacity(0.1 + 0.9 * d)
                 });
         }
@@ -55,58 +112,71 @@ fn draw_background(
 }
 
 fn draw_gene<'a>(
-    svg: &'a mut SvgDrawing,
+    svg: &'a mut Group,
     x: f32,
     y: f32,
     strand: Strand,
-    color: &StyleColor,
+    fill: &StyleColor,
     name: &str,
+    render: &RenderSettings,
 ) -> &'a mut Polygon {
+    let gene_width = render.dimensions.gene_width;
+    let theme = &render.theme;
     match strand {
         Strand::Direct => svg
             .polygon()
             .add_point(x, y)
             .add_point(x + 3., y - 5.)
-            .add_point(x + GENE_WIDTH, y - 5.)
-            .add_point(x + GENE_WIDTH, y + 5.)
+            .add_point(x + gene_width, y - 5.)
+            .add_point(x + gene_width, y + 5.)
             .add_point(x + 3., y + 5.)
             .set_hover(name)
             .style(|s| {
-                s.fill_color(Some(color.clone()))
-                    .stroke_width(0.5)
-                    .stroke_color(StyleColor::Percent(0.2, 0.2, 0.2))
+                s.fill_color(Some(theme.paint(fill.clone())))
+                    .stroke_width(theme.stroke_width)
+                    .stroke_color(color(&theme.gene_stroke))
             }),
         Strand::Reverse => svg
             .polygon()
             .add_point(x, y - 5.)
-            .add_point(x + GENE_WIDTH - 3., y - 5.)
-            .add_point(x + GENE_WIDTH, y)
-            .add_point(x + GENE_WIDTH - 3., y + 5.)
+            .add_point(x + gene_width - 3., y - 5.)
+            .add_point(x + gene_width, y)
+            .add_point(x + gene_width - 3., y + 5.)
             .add_point(x, y + 5.)
             .set_hover(name)
             .style(|s| {
-                s.fill_color(Some(color.clone()))
-                    .stroke_width(0.5)
-                    .stroke_color(StyleColor::Percent(0.2, 0.2, 0.2))
+                s.fill_color(Some(theme.paint(fill.clone())))
+                    .stroke_width(theme.stroke_width)
+                    .stroke_color(color(&theme.gene_stroke))
             }),
         Strand::Unknown => svg
             .polygon()
             .add_point(x + 1.5, y - 5.)
-            .add_point(x + GENE_WIDTH - 1.5, y - 5.)
-            .add_point(x + GENE_WIDTH - 1.5, y + 5.)
+            .add_point(x + gene_width - 1.5, y - 5.)
+            .add_point(x + gene_width - 1.5, y + 5.)
             .add_point(x + 1.5, y + 5.)
             .set_hover(name)
             .style(|s| {
-                s.fill_color(Some(color.clone()))
-                    .stroke_width(0.5)
-                    .stroke_color(StyleColor::Percent(0.2, 0.2, 0.2))
+                s.fill_color(Some(theme.paint(fill.clone())))
+                    .stroke_width(theme.stroke_width)
+                    .stroke_color(color(&theme.gene_stroke))
             }),
     }
 }
 
+/// Mark that the branch at `(x, y)` continues on `page`
+fn draw_continuation(svg: &mut Group, x: f32, y: f32, page: usize, up: bool, theme: &Theme) {
+    svg.text()
+        .pos(x + 2., if up { y + 8. } else { y })
+        .text(format!("{} p. {}", if up { "↑" } else { "↓" }, page))
+        .style(|s| s.fill_color(Some(color(&theme.missing))));
+}
+
+#[allow(clippy::too_many_arguments)]
 fn draw_tree(
-    svg: &mut SvgDrawing,
+    svg: &mut Group,
     genes: &GeneCache,
+    neighbours: &NeighbourCache,
     colormap: &ColorMap,
     petmap: &PetnameMap,
     depth: f32,
@@ -116,78 +186,137 @@ fn draw_tree(
     yoffset: f32,
     xlabels: f32,
     links: &mut Vec<(f32, Vec<FamilyID>, FamilyID, Vec<FamilyID>)>,
+    view: View,
     render: &RenderSettings,
 ) -> f32 {
+    let Dimensions {
+        gene_width,
+        gene_spacing,
+        branch_width,
+        font_size,
+    } = render.dimensions;
+    let theme = &render.theme;
     let mut y = yoffset;
     let mut old_y = 0.;
     let mut children = tree[n].children().to_vec();
     children.sort_by_key(|c| tree.name(*c).cloned().unwrap_or_else(|| "Z".to_string()));
     if children.is_empty() {
-        return y + 20.;
+        return y + ROW_HEIGHT;
     }
 
     for (i, child) in children.iter().enumerate() {
-        if i > 0 {
+        if let (true, Some((from, to))) = (i > 0, view.clip(old_y, y)) {
             svg.line()
-                .from_coords(xoffset, old_y, xoffset, y)
-                .style(|s| s.stroke_color(StyleColor::RGB(0, 0, 0)).stroke_width(0.5));
+                .from_coords(xoffset, from, xoffset, to)
+                .style(|s| {
+                    s.stroke_color(color(&theme.foreground))
+                        .stroke_width(theme.stroke_width)
+                });
+            if from > old_y {
+                draw_continuation(svg, xoffset, from, view.page_of(old_y), true, theme);
+            }
+            if to < y {
+                draw_continuation(svg, xoffset, to, view.page_of(y), false, theme);
+            }
         }
         old_y = y;
 
-        if tree[*child].is_leaf() {
+        if tree[*child].is_leaf() && !view.contains(y) {
+            // This row belongs to another page
+            y += ROW_HEIGHT;
+        } else if tree[*child].is_leaf() {
+            // Highlighted row
+            if tree
+                .name(*child)
+                .map(|name| render.highlighted_leaves.contains(name))
+                .unwrap_or(false)
+            {
+                let height = 0.8 * ROW_HEIGHT;
+                svg.polygon()
+                    .from_pos_dims(
+                        depth,
+                        y - height / 2.,
+                        xlabels + (gene_width + gene_spacing) * (2. * WINDOW as f32 + 1.) - depth,
+                        height,
+                    )
+                    .style(|s| {
+                        s.fill_color(Some(color(&theme.highlight)))
+                            .fill_opacity(0.3)
+                    });
+            }
+
             // Leaf branch
-            svg.line()
-                .from_coords(xoffset, y, depth, y)
-                .style(|s| s.stroke_color(StyleColor::RGB(0, 0, 0)).stroke_width(0.5));
+            svg.line().from_coords(xoffset, y, depth, y).style(|s| {
+                s.stroke_color(color(&theme.foreground))
+                    .stroke_width(theme.stroke_width)
+            });
 
             // Landscape support line
             svg.line()
                 .from_points([
                     (xlabels - 5., y),
                     (
-                        xlabels + (GENE_WIDTH + GENE_SPACING) * (2. * WINDOW as f32 + 1.)
-                            - GENE_SPACING
+                        xlabels + (gene_width + gene_spacing) * (2. * WINDOW as f32 + 1.)
+                            - gene_spacing
                             + 5.,
                         y,
                     ),
                 ])
-                .style(|s| s.stroke_color(StyleColor::RGB(0, 0, 0)).stroke_width(0.5));
+                .style(|s| {
+                    s.stroke_color(color(&theme.foreground))
+                        .stroke_width(theme.stroke_width)
+                });
 
             if let Some(gene_name) = tree.name(*child).as_ref() {
                 if let Some(Gene {
+                    id,
                     family,
                     species,
                     chr,
+                    pos,
                     strand,
                     left_landscape,
                     right_landscape,
-                    ..
                 }) = genes.get(gene_name.as_str())
                 {
+                    let (lefts, rights) = neighbours
+                        .get(gene_name.as_str())
+                        .map(|(lefts, rights)| (lefts.as_slice(), rights.as_slice()))
+                        .unwrap_or((&[], &[]));
+                    let hover = |family: &FamilyID, neighbour: Option<&Neighbour>| {
+                        if let Some(neighbour) = neighbour {
+                            format!("{}: {}", petmap[family], neighbour.describe(chr))
+                        } else {
+                            petmap[family].to_owned()
+                        }
+                    };
+
                     // Gene/protein name
                     svg.text()
                         .pos(depth, y + 5.)
                         .text(format!("{} {}/{}", gene_name, species, chr))
-                        .style(|s| s.fill_color(Some(name2color(species))));
+                        .style(|s| s.fill_color(Some(theme.paint(name2color(species)))));
 
                     // Left tail
-                    let xbase = xlabels + (WINDOW as f32 - 1.) * (GENE_WIDTH + GENE_SPACING);
+                    let xbase = xlabels + (WINDOW as f32 - 1.) * (gene_width + gene_spacing);
                     for (k, tg) in left_landscape.iter().enumerate() {
-                        let xstart = xbase - (k as f32) * (GENE_WIDTH + GENE_SPACING);
+                        let xstart = xbase - (k as f32) * (gene_width + gene_spacing);
                         let drawn = draw_gene(
                             svg,
                             xstart,
                             y,
                             tg.strand,
-                            colormap
+                            &colormap
                                 .get(&tg.family)
-                                .unwrap_or(&StyleColor::String("#aaa".to_string())),
-                            &petmap[&tg.family],
+                                .cloned()
+                                .unwrap_or_else(|| color(&theme.missing)),
+                            &hover(&tg.family, lefts.get(k)),
+                            render,
                         );
                         if tg.family == *family {
                             drawn.style(|s| {
-                                s.stroke_width(2.)
-                                    .stroke_color(StyleColor::Percent(0.1, 0.1, 0.1))
+                                s.stroke_width(theme.focus_stroke_width)
+                                    .stroke_color(color(&theme.focus_stroke))
                             });
                         }
                     }
@@ -195,35 +324,41 @@ fn draw_tree(
                     // The Gene
                     draw_gene(
                         svg,
-                        xlabels + WINDOW as f32 * (GENE_WIDTH + GENE_SPACING),
+                        xlabels + WINDOW as f32 * (gene_width + gene_spacing),
                         y,
                         *strand,
                         &gene2color(&family.to_ne_bytes()),
-                        &petmap[family],
+                        &format!(
+                            "{}: {} – family {} ({}) – {}:{}",
+                            petmap[family], id, family, strand, chr, pos
+                        ),
+                        render,
                     )
                     .style(|s| {
-                        s.stroke_width(2.)
-                            .stroke_color(StyleColor::Percent(0.1, 0.1, 0.1))
+                        s.stroke_width(theme.focus_stroke_width)
+                            .stroke_color(color(&theme.focus_stroke))
                     });
 
                     // Right tail
-                    let xbase = xlabels + (WINDOW as f32 + 1.) * (GENE_WIDTH + GENE_SPACING);
+                    let xbase = xlabels + (WINDOW as f32 + 1.) * (gene_width + gene_spacing);
                     for (k, tg) in right_landscape.iter().enumerate() {
-                        let xstart = xbase + (k as f32) * (GENE_WIDTH + GENE_SPACING);
+                        let xstart = xbase + (k as f32) * (gene_width + gene_spacing);
                         let drawn = draw_gene(
                             svg,
                             xstart,
                             y,
                             tg.strand,
-                            colormap
+                            &colormap
                                 .get(&tg.family)
-                                .unwrap_or(&StyleColor::String("#aaa".to_string())),
-                            &petmap[&tg.family],
+                                .cloned()
+                                .unwrap_or_else(|| color(&theme.missing)),
+                            &hover(&tg.family, rights.get(k)),
+                            render,
                         );
                         if tg.family == *family {
                             drawn.style(|s| {
-                                s.stroke_width(2.)
-                                    .stroke_color(StyleColor::Percent(0.1, 0.1, 0.1))
+                                s.stroke_width(theme.focus_stroke_width)
+                                    .stroke_color(color(&theme.focus_stroke))
                             });
                         }
                     }
@@ -241,40 +376,55 @@ fn draw_tree(
                     ));
                 } else {
                     // The node was not found in the database
-                    eprintln!("{} not found", gene_name);
+                    svg.text()
+                        .pos(depth, y + 5.)
+                        .text(format!("{} (not found)", gene_name))
+                        .style(|s| s.fill_color(Some(color(&theme.missing))));
                     links.push((y, Vec::new(), 0, Vec::new()));
                 }
             }
-            y += 20.;
+            y += ROW_HEIGHT;
         } else {
-            svg.line()
-                .from_coords(xoffset, y, xoffset + BRANCH_WIDTH, y)
-                .style(|s| s.stroke_color(StyleColor::RGB(0, 0, 0)).stroke_width(0.5));
+            if view.contains(y) {
+                svg.line()
+                    .from_coords(xoffset, y, xoffset + branch_width, y)
+                    .style(|s| {
+                        s.stroke_color(color(&theme.foreground))
+                            .stroke_width(theme.stroke_width)
+                    });
+            }
             y = draw_tree(
                 svg,
                 genes,
+                neighbours,
                 colormap,
                 petmap,
                 depth,
                 tree,
                 *child,
-                xoffset + BRANCH_WIDTH,
+                xoffset + branch_width,
                 y,
                 xlabels,
                 links,
+                view,
                 render,
             );
         }
     }
 
+    if !view.contains(yoffset) {
+        return y;
+    }
+
     let grafting_method = tree.attrs(n).get("METHOD").cloned().unwrap_or_default();
     fn caret<'a>(
-        svg: &'a mut SvgDrawing,
+        svg: &'a mut Group,
         xoffset: f32,
         yoffset: f32,
         w: f32,
         dcs: Option<f32>,
         method: &'a str,
+        theme: &Theme,
     ) {
         match method {
             "ELC" => {
@@ -285,9 +435,9 @@ fn draw_tree(
                     .radius(w / 2.)
                     .style(|s| {
                         s.fill_color(Some(if let Some(dcs) = dcs {
-                            StyleColor::Percent(1.0 - dcs, dcs, 0.)
+                            theme.score(dcs)
                         } else {
-                            StyleColor::Percent(0., 0., 0.)
+                            color(&theme.foreground)
                         }))
                     });
             }
@@ -298,9 +448,9 @@ fn draw_tree(
                     .transform(|c| c.rotate_from(45., xoffset, yoffset))
                     .style(|s| {
                         s.fill_color(Some(if let Some(dcs) = dcs {
-                            StyleColor::Percent(1.0 - dcs, dcs, 0.)
+                            theme.score(dcs)
                         } else {
-                            StyleColor::Percent(0., 0., 0.)
+                            color(&theme.foreground)
                         }))
                     });
             }
@@ -310,9 +460,9 @@ fn draw_tree(
                     .from_pos_dims(xoffset - w / 2., yoffset - w / 2., w, w)
                     .style(|s| {
                         s.fill_color(Some(if let Some(dcs) = dcs {
-                            StyleColor::Percent(1.0 - dcs, dcs, 0.)
+                            theme.score(dcs)
                         } else {
-                            StyleColor::Percent(0., 0., 0.)
+                            color(&theme.foreground)
                         }))
                     });
             }
@@ -322,7 +472,7 @@ fn draw_tree(
                         .polygon()
                         .from_pos_dims(xoffset - w / 2., yoffset - w / 2., w, w)
                         .style(|s| {
-                            s.stroke_color(StyleColor::Percent(1.0 - dcs, dcs, 0.))
+                            s.stroke_color(theme.score(dcs))
                                 .fill_color(None)
                                 .stroke_width(2.)
                         });
@@ -335,8 +485,8 @@ fn draw_tree(
         if let Some(annotation) = tree.attrs(n).get(annotation) {
             svg.text()
                 .pos(
-                    xoffset - FONT_SIZE,
-                    yoffset + FONT_SIZE + 1.1 * label_offset as f32,
+                    xoffset - font_size,
+                    yoffset + font_size + 1.1 * label_offset as f32,
                 )
                 .text(annotation);
         }
@@ -353,13 +503,14 @@ fn draw_tree(
             .get("DCS")
             .and_then(|dcs| str::parse::<f32>(dcs).ok()),
         &grafting_method,
+        theme,
     );
 
     if render.inner_tags {
         tree.attrs(n).get("S").map(|name| {
             svg.text()
-                .pos(xoffset, yoffset - FONT_SIZE)
-                .transform(|t| t.rotate_from(-30., xoffset, yoffset - FONT_SIZE))
+                .pos(xoffset, yoffset - font_size)
+                .transform(|t| t.rotate_from(-30., xoffset, yoffset - font_size))
                 .text(name)
         });
     }
@@ -368,45 +519,51 @@ fn draw_tree(
 }
 
 fn draw_links(
-    svg: &mut SvgDrawing,
+    svg: &mut Group,
     links: &[(f32, Vec<FamilyID>, FamilyID, Vec<FamilyID>)],
     xlabels: f32,
+    render: &RenderSettings,
 ) {
+    let Dimensions {
+        gene_width,
+        gene_spacing,
+        ..
+    } = render.dimensions;
     for w in links.windows(2) {
-        let xbase = xlabels + (WINDOW as f32 - 1.) * (GENE_WIDTH + GENE_SPACING);
+        let xbase = xlabels + (WINDOW as f32 - 1.) * (gene_width + gene_spacing);
         for (i, ancestral) in w[0].1.iter().enumerate() {
-            let x1 = xbase - i as f32 * (GENE_WIDTH + GENE_SPACING) + GENE_WIDTH / 2.;
+            let x1 = xbase - i as f32 * (gene_width + gene_spacing) + gene_width / 2.;
             for j in
                 w[1].1
                     .iter()
                     .enumerate()
                     .filter_map(|(j, name)| if name == ancestral { Some(j) } else { None })
             {
-                let x2 = xbase - j as f32 * (GENE_WIDTH + GENE_SPACING) + GENE_WIDTH / 2.;
+                let x2 = xbase - j as f32 * (gene_width + gene_spacing) + gene_width / 2.;
                 svg.line()
                     .from_points([(x1, w[0].0 + 5.), (x2, w[1].0 - 5.)])
                     .style(|s| {
-                        s.stroke_color(StyleColor::String("#000".into()))
+                        s.stroke_color(color(&render.theme.foreground))
                             .stroke_width(1.0)
                             .dashed(&[2, 2])
                     });
             }
         }
 
-        let xbase = xlabels + (WINDOW as f32 + 1.) * (GENE_WIDTH + GENE_SPACING);
+        let xbase = xlabels + (WINDOW as f32 + 1.) * (gene_width + gene_spacing);
         for (i, ancestral) in w[0].3.iter().enumerate() {
-            let x1 = xbase + i as f32 * (GENE_WIDTH + GENE_SPACING) + GENE_WIDTH / 2.;
+            let x1 = xbase + i as f32 * (gene_width + gene_spacing) + gene_width / 2.;
             for j in
                 w[1].3
                     .iter()
                     .enumerate()
                     .filter_map(|(j, name)| if name == ancestral { Some(j) } else { None })
             {
-                let x2 = xbase + j as f32 * (GENE_WIDTH + GENE_SPACING) + GENE_WIDTH / 2.;
+                let x2 = xbase + j as f32 * (gene_width + gene_spacing) + gene_width / 2.;
                 svg.line()
                     .from_points([(x1, w[0].0 + 5.), (x2, w[1].0 - 5.)])
                     .style(|s| {
-                        s.stroke_color(StyleColor::String("#000".into()))
+                        s.stroke_color(color(&render.theme.foreground))
                             .stroke_width(1.0)
                             .dashed(&[2, 2])
                     });
@@ -415,30 +572,59 @@ fn draw_links(
     }
 }
 
-pub fn render(
-    t: &NewickTree,
-    genes: &GeneCache,
-    colormap: &ColorMap,
-    petmap: &PetnameMap,
-    out_filename: &str,
-    render: &RenderSettings,
-) {
-    let depth = BRANCH_WIDTH * (t.topological_depth().1 as f32 + 1.);
-    let longest_name = (t.leaf_names().map(|name| name.len()).max().unwrap() as f32
+/// The abscissas of the leaf names and of the landscapes, and the width of
+/// the plot
+fn layout(t: &NewickTree, render: &RenderSettings) -> Result<(f32, f32, f32)> {
+    let Dimensions {
+        gene_width,
+        gene_spacing,
+        branch_width,
+        font_size,
+    } = render.dimensions;
+    let depth = branch_width * (t.topological_depth().1 as f32 + 1.);
+    let longest_name = (t
+        .leaf_names()
+        .map(|name| name.len())
+        .max()
+        .context("the tree has no named leaf")? as f32
         + t.leaves()
             .map(|l| t.attrs(l).get("S").map(|s| s.len()).unwrap_or(0))
             .max()
-            .unwrap() as f32
+            .unwrap_or(0) as f32
         + 20.)
-        * FONT_SIZE;
+        * font_size;
     let xlabels = 0.85 * (10. + depth + longest_name + 20.);
-    let width = xlabels + (2. * WINDOW as f32 + 1.) * (GENE_WIDTH + GENE_SPACING) + 60.;
-    let mut svg = SvgDrawing::new();
-    draw_background(&mut svg, depth, t, t.root(), 10.0, MARGIN_TOP, width);
+    let width = xlabels + (2. * WINDOW as f32 + 1.) * (gene_width + gene_spacing) + 60.;
+    Ok((depth, xlabels, width))
+}
+
+fn draw(
+    t: &NewickTree,
+    genes: &GeneCache,
+    neighbours: &NeighbourCache,
+    colormap: &ColorMap,
+    petmap: &PetnameMap,
+    view: View,
+    render: &RenderSettings,
+) -> Result<Group> {
+    let (depth, xlabels, width) = layout(t, render)?;
+    let mut svg = Group::new();
+    draw_background(
+        &mut svg,
+        depth,
+        t,
+        t.root(),
+        10.0,
+        MARGIN_TOP,
+        width,
+        view,
+        render,
+    );
     let mut links = Vec::new();
     draw_tree(
         &mut svg,
         genes,
+        neighbours,
         colormap,
         petmap,
         depth,
@@ -448,12 +634,127 @@ pub fn render(
         MARGIN_TOP,
         xlabels,
         &mut links,
+        view,
         render,
     );
     if render.links {
-        draw_links(&mut svg, &links, xlabels);
+        draw_links(&mut svg, &links, xlabels, render);
+    }
+    Ok(svg)
+}
+
+/// Label the landscape columns by their distance to the central gene, and
+/// draw their guides along the rows of `view`
+fn draw_header(
+    svg: &mut Group,
+    t: &NewickTree,
+    view: View,
+    page: usize,
+    pages: usize,
+    render: &RenderSettings,
+) -> Result<()> {
+    let (_, xlabels, _) = layout(t, render)?;
+    let step = render.dimensions.gene_width + render.dimensions.gene_spacing;
+    let theme = &render.theme;
+    let y = view.top - ROW_HEIGHT;
+
+    svg.text()
+        .pos(10., y)
+        .text(format!("page {}/{}", page, pages));
+    for column in 0..=2 * WINDOW {
+        let x = xlabels + column as f32 * step + render.dimensions.gene_width / 2.;
+        svg.text()
+            .pos(x, y)
+            .text(format!("{:+}", column as i64 - WINDOW as i64))
+            .style(|s| s.set("text-anchor", "middle"));
+        svg.line()
+            .from_coords(x, y + ROW_HEIGHT / 4., x, view.bottom - ROW_HEIGHT / 2.)
+            .style(|s| {
+                s.stroke_color(color(&theme.missing))
+                    .stroke_width(theme.stroke_width)
+                    .stroke_opacity(0.5)
+            });
+    }
+    Ok(())
+}
+
+/// Render `t` as an SVG written to `out`, each leaf being followed by its
+/// syntenic landscape as colored by `colormap`; `neighbours` and `petmap`
+/// provide the hover texts of the genes.
+pub fn render(
+    t: &NewickTree,
+    genes: &GeneCache,
+    neighbours: &NeighbourCache,
+    colormap: &ColorMap,
+    petmap: &PetnameMap,
+    out: &mut impl Write,
+    render: &RenderSettings,
+) -> Result<()> {
+    let svg = draw(t, genes, neighbours, colormap, petmap, View::ALL, render)?;
+    write_svg(svg, &render.theme, out)
+}
+
+/// Render `t` as `render` does, but split in SVG pages of `page_size` leaves;
+/// each page repeats the tree scaffold and a header labelling the landscape
+/// columns, and marks where its branches continue on the other pages.
+#[allow(clippy::too_many_arguments)]
+pub fn render_pages(
+    t: &NewickTree,
+    genes: &GeneCache,
+    neighbours: &NeighbourCache,
+    colormap: &ColorMap,
+    petmap: &PetnameMap,
+    page_size: usize,
+    render: &RenderSettings,
+) -> Result<Vec<String>> {
+    ensure!(page_size > 0, "pages should contain at least one leaf");
+    let pages = t.leaves().count().div_ceil(page_size);
+    (0..pages)
+        .map(|page| {
+            let view = View::page(page, page_size);
+            let mut svg = draw(t, genes, neighbours, colormap, petmap, view, render)?;
+            draw_header(&mut svg, t, view, page + 1, pages, render)?;
+            svg.shift(0., MARGIN_TOP - view.top);
+            let mut out = Vec::new();
+            write_svg(svg, &render.theme, &mut out)?;
+            Ok(String::from_utf8(out)?)
+        })
+        .collect()
+}
+
+/// Render `t` in pages as `render_pages` does, written to
+/// `{stem}-flat-{page}.svg`, or to a single multi-page `{stem}-flat.pdf` if
+/// `pdf` is set; return the names of the written files.
+#[allow(clippy::too_many_arguments)]
+pub fn write_pages(
+    t: &NewickTree,
+    genes: &GeneCache,
+    neighbours: &NeighbourCache,
+    colormap: &ColorMap,
+    petmap: &PetnameMap,
+    page_size: usize,
+    stem: &str,
+    pdf: bool,
+    render: &RenderSettings,
+) -> Result<Vec<String>> {
+    let pages = render_pages(t, genes, neighbours, colormap, petmap, page_size, render)?;
+    if pdf {
+        let filename = format!("{}-flat.pdf", stem);
+        let mut out = crate::utils::create(&filename)?;
+        out.write_all(&super::svgs_to_pdf(&pages)?)?;
+        out.flush()?;
+        return Ok(vec![filename]);
     }
-    svg.auto_fit();
-    let mut out = File::create(out_filename).unwrap();
-    out.write_all(svg.render_svg().as_bytes()).unwrap();
+    pages
+        .iter()
+        .enumerate()
+        .map(|(i, page)| {
+            let filename = format!("{}-flat-{}.svg", stem, i + 1);
+            super::write_plot(Some(&filename), false, |w| {
+                w.extend_from_slice(page.as_bytes());
+                Ok(())
+            })?;
+            Ok(filename)
+        })
+        .collect()
 }
@@ -1,12 +1,13 @@
 use crate::align;
+use crate::db::{Neighbour, NeighbourCache};
 use crate::utils::*;
+use anyhow::Result;
 use askama::Template;
 use newick::*;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::prelude::*;
-use syntesuite::genebook::Gene;
+use std::io::Write;
+use syntesuite::genebook::{FamilyID, Gene, TailGene};
 
 #[derive(Serialize, Deserialize)]
 struct PolyGene {
@@ -16,6 +17,9 @@ struct PolyGene {
 struct HtmlGene {
     color: String,
     name: String,
+    /// The details of the actual gene, shown when hovering it
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    title: Option<String>,
 }
 #[derive(Serialize, Deserialize)]
 struct Landscape {
@@ -37,140 +41,281 @@ struct HtmlNode {
     confidence: f32,
     repr: Landscape,
     clustered: Option<Vec<PolyGene>>,
+    /// The node the alignment is to be fetched for from the API
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    node: Option<usize>,
 }
 
-fn draw_html(tree: &NewickTree, genes: &GeneCache, colormap: &ColorMap) -> HtmlNode {
-    fn process(tree: &NewickTree, node: usize, genes: &GeneCache, colormap: &ColorMap) -> HtmlNode {
-        let descendants = tree.descendants(node);
-        let mut common_ancestral = 0;
-        let clustered = {
-            // Node ID to tail mapping
-            let tails = descendants
-                .iter()
-                .filter_map(|&d| {
-                    if let Some(gene_name) = tree.name(d) {
-                        if let Some(Gene {
-                            family,
-                            left_landscape,
-                            right_landscape,
-                            ..
-                        }) = genes.get(gene_name)
-                        {
-                            common_ancestral = *family;
-                            Some((
-                                d,
-                                left_landscape
-                                    .iter()
-                                    .map(|tg| PoaElt::Gene(tg.family))
-                                    .rev() // XXX Pour que les POA partent bien du bout
-                                    .chain(std::iter::once(PoaElt::Marker))
-                                    .chain(right_landscape.iter().map(|tg| PoaElt::Gene(tg.family)))
-                                    .collect::<Vec<_>>(),
-                            ))
-                        } else {
-                            None
-                        }
-                    } else {
-                        None
-                    }
-                })
-                .collect::<HashMap<_, _>>();
+#[derive(Serialize)]
+struct SpeciesNode {
+    name: String,
+    children: Vec<SpeciesNode>,
+}
 
-            if !tails.is_empty() {
-                let (g, heads) = align::align(&tails);
-                let mut alignment = align::poa_to_strings(&g, &heads)
-                    .values()
-                    .cloned()
-                    .collect::<Vec<_>>();
-                if false {
-                    // Differentiate between tail of shorter alignments and actual indels
-                    alignment.iter_mut().for_each(|a| {
-                        for pos in a.iter_mut() {
-                            if *pos == PoaElt::Indel {
-                                *pos = PoaElt::Empty;
-                            } else {
-                                break;
-                            }
-                        }
-                    });
-                }
+fn draw_species_tree(tree: &NewickTree, node: usize) -> SpeciesNode {
+    SpeciesNode {
+        name: tree.name(node).cloned().unwrap_or_default(),
+        children: tree[node]
+            .children()
+            .iter()
+            .map(|c| draw_species_tree(tree, *c))
+            .collect(),
+    }
+}
 
-                Some(
-                    (0..alignment[0].len())
-                        .map(|i| {
-                            // for kk in alignment.iter().map(|a| &a[i]) {
-                            //     let kkk = kk.chars().take(18).collect::<String>();
-                            //     print!(" {:<18} ", kkk);
-                            // }
-                            let count = if false {
-                                alignment.iter().filter(|a| a[i] != PoaElt::Empty).count() as f32
-                            } else {
-                                alignment.len() as f32
-                            };
+fn gene_title(gene: &Gene) -> String {
+    format!(
+        "{} – family {} ({}) – {}:{}",
+        gene.id, gene.family, gene.strand, gene.chr, gene.pos
+    )
+}
 
-                            let mut counts: HashMap<PoaElt, i32> = HashMap::new();
-                            alignment
-                                .iter()
-                                .map(|a| &a[i])
-                                .for_each(|g| *counts.entry(*g).or_insert(0) += 1);
-                            PolyGene {
-                                genes: counts
-                                    .into_iter()
-                                    .filter_map(|(name, v)| {
-                                        // if DROP_EMPTY && (name == EMPTY || name == INDEL) {
-                                        //     None
-                                        // } else {
-                                        let name = if name != PoaElt::Marker {
-                                            name
-                                        } else {
-                                            PoaElt::Gene(common_ancestral)
-                                        };
-                                        Some((
-                                            HtmlGene {
-                                                name: name.to_string(),
-                                                color: match name {
-                                                    PoaElt::Gene(family) => colormap
-                                                        .get(&family)
-                                                        .map(|c| c.to_hex_string())
-                                                        .unwrap_or_else(|| "#aaa".to_string()),
-                                                    PoaElt::Marker => todo!(),
-                                                    PoaElt::Indel | PoaElt::Empty => {
-                                                        "#ccc".to_string()
-                                                    }
-                                                },
-                                            },
-                                            v as f32 / count,
-                                        ))
-                                        // }
-                                    })
-                                    .collect::<Vec<(HtmlGene, f32)>>(),
-                            }
-                        })
-                        .collect::<Vec<PolyGene>>(),
-                )
-            } else {
-                None
-            }
-        };
+fn leaf_landscape(
+    gene_name: &str,
+    left_landscape: &[TailGene],
+    right_landscape: &[TailGene],
+    chr: &str,
+    neighbours: &NeighbourCache,
+    colormap: &ColorMap,
+) -> (Vec<HtmlGene>, Vec<HtmlGene>) {
+    let (neighbour_lefts, neighbour_rights) = neighbours
+        .get(gene_name)
+        .map(|(lefts, rights)| (lefts.as_slice(), rights.as_slice()))
+        .unwrap_or((&[], &[]));
+    let html_gene = |g: &TailGene, neighbour: Option<&Neighbour>| HtmlGene {
+        name: g.family.to_string(),
+        color: colormap
+            .get(&g.family)
+            .map(|c| c.to_hex_string())
+            .unwrap_or_else(|| "#aaa".to_string()),
+        title: neighbour.map(|n| n.describe(chr)),
+    };
+    // Neighbours are drawn mirrored, as were the landscapes before
+    let ((lefts, neighbour_lefts), (rights, neighbour_rights)) = (
+        (right_landscape, neighbour_rights),
+        (left_landscape, neighbour_lefts),
+    );
+    (
+        lefts
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(i, g)| html_gene(g, neighbour_lefts.get(i)))
+            .collect(),
+        rights
+            .iter()
+            .enumerate()
+            .map(|(i, g)| html_gene(g, neighbour_rights.get(i)))
+            .collect(),
+    )
+}
 
-        let ((species, chr, gene, ancestral), (lefts, rights)) =
-            if let Some(gene_name) = &tree.name(node) {
+/// The JSON landscape of the leaf `gene_name`, as embedded in the HTML data
+/// model
+pub fn landscape(
+    gene_name: &str,
+    genes: &GeneCache,
+    neighbours: &NeighbourCache,
+    colormap: &ColorMap,
+) -> Option<String> {
+    genes.get(gene_name).map(|gene| {
+        let (lefts, rights) = leaf_landscape(
+            gene_name,
+            &gene.left_landscape,
+            &gene.right_landscape,
+            &gene.chr,
+            neighbours,
+            colormap,
+        );
+        serde_json::to_string(&Landscape {
+            lefts,
+            rights,
+            me: HtmlGene {
+                color: gene2color(&gene.family.to_ne_bytes()).to_hex_string(),
+                name: gene.family.to_string(),
+                title: Some(gene_title(gene)),
+            },
+        })
+        .unwrap()
+    })
+}
+
+/// The alignment of the landscapes of the leaves under `node`, as a
+/// distribution of the families at each position
+#[allow(clippy::unnecessary_filter_map)]
+fn cluster(
+    tree: &NewickTree,
+    node: usize,
+    genes: &GeneCache,
+    colormap: &ColorMap,
+) -> Option<Vec<PolyGene>> {
+    let descendants = tree.descendants(node);
+    let common_ancestral = common_family(tree, &descendants, genes);
+    // Node ID to tail mapping
+    let tails = descendants
+        .iter()
+        .filter_map(|&d| {
+            if let Some(gene_name) = tree.name(d) {
                 if let Some(Gene {
-                    family,
-                    species,
-                    chr,
                     left_landscape,
                     right_landscape,
                     ..
-                }) = genes.get(gene_name.as_str())
+                }) = genes.get(gene_name)
                 {
-                    common_ancestral = *family;
-                    let (proto_lefts, proto_rights) = (left_landscape, right_landscape);
-                    let (lefts, rights) = if true {
-                        (proto_rights, proto_lefts)
+                    Some((
+                        d,
+                        left_landscape
+                            .iter()
+                            .map(|tg| PoaElt::Gene(tg.family))
+                            .rev() // XXX Pour que les POA partent bien du bout
+                            .chain(std::iter::once(PoaElt::Marker))
+                            .chain(right_landscape.iter().map(|tg| PoaElt::Gene(tg.family)))
+                            .collect::<Vec<_>>(),
+                    ))
+                } else {
+                    None
+                }
+            } else {
+                None
+            }
+        })
+        .collect::<HashMap<_, _>>();
+
+    if !tails.is_empty() {
+        let (g, heads) = align::align(&tails);
+        let mut alignment = align::poa_to_strings(&g, &heads)
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        if false {
+            // Differentiate between tail of shorter alignments and actual indels
+            alignment.iter_mut().for_each(|a| {
+                for pos in a.iter_mut() {
+                    if *pos == PoaElt::Indel {
+                        *pos = PoaElt::Empty;
                     } else {
-                        (proto_lefts, proto_rights)
+                        break;
+                    }
+                }
+            });
+        }
+
+        Some(
+            (0..alignment[0].len())
+                .map(|i| {
+                    // for kk in alignment.iter().map(|a| &a[i]) {
+                    //     let kkk = kk.chars().take(18).collect::<String>();
+                    //     print!(" {:<18} ", kkk);
+                    // }
+                    let count = if false {
+                        alignment.iter().filter(|a| a[i] != PoaElt::Empty).count() as f32
+                    } else {
+                        alignment.len() as f32
                     };
+
+                    let mut counts: HashMap<PoaElt, i32> = HashMap::new();
+                    alignment
+                        .iter()
+                        .map(|a| &a[i])
+                        .for_each(|g| *counts.entry(*g).or_insert(0) += 1);
+                    PolyGene {
+                        genes: counts
+                            .into_iter()
+                            .filter_map(|(name, v)| {
+                                // if DROP_EMPTY && (name == EMPTY || name == INDEL) {
+                                //     None
+                                // } else {
+                                let name = if name != PoaElt::Marker {
+                                    name
+                                } else {
+                                    PoaElt::Gene(common_ancestral)
+                                };
+                                Some((
+                                    HtmlGene {
+                                        name: name.to_string(),
+                                        color: match name {
+                                            PoaElt::Gene(family) => colormap
+                                                .get(&family)
+                                                .map(|c| c.to_hex_string())
+                                                .unwrap_or_else(|| "#aaa".to_string()),
+                                            PoaElt::Marker => todo!(),
+                                            PoaElt::Indel | PoaElt::Empty => "#ccc".to_string(),
+                                        },
+                                        title: None,
+                                    },
+                                    v as f32 / count,
+                                ))
+                                // }
+                            })
+                            .collect::<Vec<(HtmlGene, f32)>>(),
+                    }
+                })
+                .collect::<Vec<PolyGene>>(),
+        )
+    } else {
+        None
+    }
+}
+
+/// The family of the last of the `descendants` leaves found in `genes`
+fn common_family(tree: &NewickTree, descendants: &[usize], genes: &GeneCache) -> FamilyID {
+    descendants
+        .iter()
+        .rev()
+        .filter_map(|&d| tree.name(d))
+        .find_map(|name| genes.get(name))
+        .map(|gene| gene.family)
+        .unwrap_or_default()
+}
+
+/// The JSON alignment of the landscapes under `node`, as embedded in the
+/// HTML data model
+pub fn clustered(
+    tree: &NewickTree,
+    node: usize,
+    genes: &GeneCache,
+    colormap: &ColorMap,
+) -> Option<String> {
+    cluster(tree, node, genes, colormap).map(|c| serde_json::to_string(&c).unwrap())
+}
+
+fn draw_html(
+    tree: &NewickTree,
+    genes: &GeneCache,
+    neighbours: &NeighbourCache,
+    colormap: &ColorMap,
+    lazy: bool,
+) -> HtmlNode {
+    fn process(
+        tree: &NewickTree,
+        node: usize,
+        genes: &GeneCache,
+        neighbours: &NeighbourCache,
+        colormap: &ColorMap,
+        lazy: bool,
+    ) -> HtmlNode {
+        // The alignments are left for the API to compute on demand when lazy
+        let (clustered, lazy_node) = if lazy && !tree[node].is_leaf() {
+            (None, Some(node))
+        } else {
+            (cluster(tree, node, genes, colormap), None)
+        };
+        let mut common_ancestral = common_family(tree, &tree.descendants(node), genes);
+
+        let mut me_title = None;
+        let ((species, chr, gene, ancestral), (lefts, rights)) =
+            if let Some(gene_name) = &tree.name(node) {
+                if let Some(gene) = genes.get(gene_name.as_str()) {
+                    let Gene {
+                        family,
+                        species,
+                        chr,
+                        left_landscape,
+                        right_landscape,
+                        ..
+                    } = gene;
+                    common_ancestral = *family;
+                    me_title = Some(gene_title(gene));
                     (
                         (
                             species.to_owned(),
@@ -178,29 +323,18 @@ fn draw_html(tree: &NewickTree, genes: &GeneCache, colormap: &ColorMap) -> HtmlN
                             gene_name.to_string(),
                             *family,
                         ),
-                        (
-                            lefts
-                                .iter()
-                                .rev()
-                                .map(|g| HtmlGene {
-                                    name: g.family.to_string(),
-                                    color: colormap
-                                        .get(&g.family)
-                                        .map(|c| c.to_hex_string())
-                                        .unwrap_or_else(|| "#aaa".to_string()),
-                                })
-                                .collect::<Vec<_>>(),
-                            rights
-                                .iter()
-                                .map(|g| HtmlGene {
-                                    name: g.family.to_string(),
-                                    color: colormap
-                                        .get(&g.family)
-                                        .map(|c| c.to_hex_string())
-                                        .unwrap_or_else(|| "#aaa".to_string()),
-                                })
-                                .collect::<Vec<_>>(),
-                        ),
+                        if lazy {
+                            (vec![], vec![])
+                        } else {
+                            leaf_landscape(
+                                gene_name,
+                                left_landscape,
+                                right_landscape,
+                                chr,
+                                neighbours,
+                                colormap,
+                            )
+                        },
                     )
                 } else {
                     (
@@ -226,7 +360,7 @@ fn draw_html(tree: &NewickTree, genes: &GeneCache, colormap: &ColorMap) -> HtmlN
                 .children()
                 .as_ref()
                 .iter()
-                .map(|n| process(tree, *n, genes, colormap))
+                .map(|n| process(tree, *n, genes, neighbours, colormap, lazy))
                 .collect(),
             is_duplication: tree.is_duplication(node),
             confidence: tree
@@ -240,16 +374,39 @@ fn draw_html(tree: &NewickTree, genes: &GeneCache, colormap: &ColorMap) -> HtmlN
                 me: HtmlGene {
                     color: gene2color(&common_ancestral.to_ne_bytes()).to_hex_string(),
                     name: common_ancestral.to_string(),
+                    title: me_title,
                 },
             },
             clustered,
+            node: lazy_node,
         }
     }
 
-    process(tree, tree.root(), genes, colormap)
+    process(tree, tree.root(), genes, neighbours, colormap, lazy)
+}
+
+/// The JSON data model of the HTML viewer for `t`; if `lazy`, the landscapes
+/// of the leaves and the alignments of the inner nodes are left out, to be
+/// fetched from the API given to `page`.
+pub fn data(
+    t: &NewickTree,
+    genes: &GeneCache,
+    neighbours: &NeighbourCache,
+    colormap: &ColorMap,
+    lazy: bool,
+) -> String {
+    serde_json::to_string(&draw_html(t, genes, neighbours, colormap, lazy)).unwrap()
 }
 
-pub fn render(t: &NewickTree, genes: &GeneCache, colormap: &ColorMap, out_filename: &str) {
+/// A standalone HTML viewer of `data`; `api`, if any, is the URL prefix the
+/// lazy landscapes are fetched from.
+pub fn page(
+    title: &str,
+    data: &str,
+    species_tree: Option<&NewickTree>,
+    render: &RenderSettings,
+    api: Option<&str>,
+) -> Result<String> {
     #[derive(Template)]
     #[template(path = "genominicus.html", escape = "none")]
     struct GenominicusTemplate<'a> {
@@ -261,16 +418,126 @@ pub fn render(t: &NewickTree, genes: &GeneCache, colormap: &ColorMap, out_filena
         comment: &'a str,
 
         data: &'a str,
+        links: &'a str,
+        species_tree: &'a str,
+        api: &'a str,
     }
 
-    let html = GenominicusTemplate {
+    GenominicusTemplate {
         css: include_str!("../../templates/genominicus.css"),
         js_genominicus: include_str!("../../templates/genominicus.js"),
         js_svg: include_str!("../../templates/svg.min.js"),
-        title: out_filename,
+        title,
         comment: "",
-        data: &serde_json::to_string_pretty(&draw_html(t, genes, colormap)).unwrap(),
-    };
-    let mut out = File::create(out_filename).unwrap();
-    let _ = out.write(html.render().unwrap().as_bytes()).unwrap();
+        data,
+        links: &serde_json::to_string(&render.link_templates)?,
+        species_tree: &serde_json::to_string(
+            &species_tree.map(|species_tree| draw_species_tree(species_tree, species_tree.root())),
+        )?,
+        api: &serde_json::to_string(&api)?,
+    }
+    .render()
+    .map_err(Into::into)
+}
+
+/// Render `t` as a standalone interactive HTML page titled `title`, written to
+/// `out`; `species_tree`, if any, is shown as a side panel.
+///
+/// A standalone page has no server to align the landscapes of a node when it
+/// is expanded, and the alignment can not run in the browser; the alignments
+/// of all the inner nodes are thus computed and embedded beforehand. `serve`
+/// computes them on demand instead, and should be preferred for huge trees.
+#[allow(clippy::too_many_arguments)]
+pub fn render(
+    t: &NewickTree,
+    genes: &GeneCache,
+    neighbours: &NeighbourCache,
+    colormap: &ColorMap,
+    species_tree: Option<&NewickTree>,
+    title: &str,
+    out: &mut impl Write,
+    render: &RenderSettings,
+) -> Result<()> {
+    let html = page(
+        title,
+        &data(t, genes, neighbours, colormap, false),
+        species_tree,
+        render,
+        None,
+    )?;
+    out.write_all(html.as_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use syntesuite::Strand;
+
+    fn genes(landscapes: &[(&str, &[FamilyID])]) -> GeneCache {
+        landscapes
+            .iter()
+            .map(|(name, families)| {
+                let tail = families
+                    .iter()
+                    .map(|&family| TailGene {
+                        family,
+                        strand: Strand::Direct,
+                    })
+                    .collect::<Vec<_>>();
+                let gene = Gene {
+                    id: name.to_string(),
+                    species: "hsapiens".to_owned(),
+                    family: 1,
+                    chr: "1".to_owned(),
+                    pos: 0,
+                    strand: Strand::Direct,
+                    left_landscape: tail.clone(),
+                    right_landscape: tail,
+                };
+                (name.to_string(), gene)
+            })
+            .collect()
+    }
+
+    fn inner_nodes(node: &serde_json::Value) -> Vec<&serde_json::Value> {
+        let children = node["children"].as_array().unwrap();
+        if children.is_empty() {
+            Vec::new()
+        } else {
+            std::iter::once(node)
+                .chain(children.iter().flat_map(inner_nodes))
+                .collect()
+        }
+    }
+
+    #[test]
+    fn lazy_alignments() {
+        let t = newick::one_from_string("((a,b),c);").unwrap();
+        let genes = genes(&[("a", &[2, 3]), ("b", &[2, 4]), ("c", &[5])]);
+        let data = |lazy| {
+            serde_json::from_str::<serde_json::Value>(&data(
+                &t,
+                &genes,
+                &NeighbourCache::new(),
+                &ColorMap::new(),
+                lazy,
+            ))
+            .unwrap()
+        };
+
+        let eager = data(false);
+        let inners = inner_nodes(&eager);
+        assert_eq!(inners.len(), 2);
+        assert!(inners
+            .iter()
+            .all(|n| n["clustered"].is_array() && n.get("node").is_none()));
+
+        let lazy = data(true);
+        let inners = inner_nodes(&lazy);
+        assert_eq!(inners.len(), 2);
+        assert!(inners
+            .iter()
+            .all(|n| n["clustered"].is_null() && n["node"].is_u64()));
+    }
 }
@@ -2,3 +2,90 @@ pub mod barcode;
 pub mod flat;
 pub mod html;
 pub mod skeleton;
+pub mod theme;
+
+use anyhow::*;
+use pdf_writer::{Content, Finish, Name, Pdf, Rect, Ref};
+use std::io::Write;
+use svg2pdf::usvg::{fontdb, PostProcessingSteps, TreeParsing, TreePostProc};
+
+/// Parse an SVG plot, with its texts set in `fonts`
+fn parse_svg(svg: &str, fonts: &fontdb::Database) -> Result<svg2pdf::usvg::Tree> {
+    let mut tree = svg2pdf::usvg::Tree::from_str(svg, &svg2pdf::usvg::Options::default())
+        .context("failed to parse SVG")?;
+    tree.postprocess(PostProcessingSteps::default(), fonts);
+    Ok(tree)
+}
+
+fn system_fonts() -> fontdb::Database {
+    let mut fonts = fontdb::Database::new();
+    fonts.load_system_fonts();
+    fonts
+}
+
+/// Convert an SVG plot to a PDF document, with its texts set in the system
+/// fonts
+pub fn svg_to_pdf(svg: &str) -> Result<Vec<u8>> {
+    let tree = parse_svg(svg, &system_fonts())?;
+    Ok(svg2pdf::convert_tree(&tree, svg2pdf::Options::default()))
+}
+
+/// Convert SVG plots to a single PDF document, one page per plot
+pub fn svgs_to_pdf(svgs: &[String]) -> Result<Vec<u8>> {
+    let fonts = system_fonts();
+    let mut pdf = Pdf::new();
+    let mut alloc = Ref::new(1);
+    let catalog_id = alloc.bump();
+    let page_tree_id = alloc.bump();
+    let plot_name = Name(b"P");
+    let mut page_ids = Vec::with_capacity(svgs.len());
+    for svg in svgs {
+        let tree = parse_svg(svg, &fonts)?;
+        let (width, height) = (tree.size.width(), tree.size.height());
+        let page_id = alloc.bump();
+        let content_id = alloc.bump();
+        let plot_id = alloc.bump();
+        // The plot is drawn as a unit-sized form, scaled to the page
+        alloc = svg2pdf::convert_tree_into(&tree, svg2pdf::Options::default(), &mut pdf, plot_id);
+
+        let mut page = pdf.page(page_id);
+        page.media_box(Rect::new(0., 0., width, height));
+        page.parent(page_tree_id);
+        page.contents(content_id);
+        page.resources().x_objects().pair(plot_name, plot_id);
+        page.finish();
+
+        let mut content = Content::new();
+        content
+            .transform([width, 0., 0., height, 0., 0.])
+            .x_object(plot_name);
+        pdf.stream(content_id, &content.finish());
+        page_ids.push(page_id);
+    }
+    pdf.catalog(catalog_id).pages(page_tree_id);
+    pdf.pages(page_tree_id)
+        .kids(page_ids.iter().copied())
+        .count(page_ids.len() as i32);
+    Ok(pdf.finish())
+}
+
+/// Write the SVG plot produced by `render` to `filename`, or to the standard
+/// output if it is `None`, converted to PDF if `pdf` is set
+pub fn write_plot(
+    filename: Option<&str>,
+    pdf: bool,
+    render: impl FnOnce(&mut Vec<u8>) -> Result<()>,
+) -> Result<()> {
+    let mut svg = Vec::new();
+    render(&mut svg)?;
+    let content = if pdf {
+        svg_to_pdf(std::str::from_utf8(&svg)?)?
+    } else {
+        svg
+    };
+    let mut out = crate::utils::output(filename)?;
+    out.write_all(&content)?;
+    // Write errors would otherwise be swallowed when the buffer is dropped
+    out.flush()?;
+    Ok(())
+}
@@ -1,6 +1,7 @@
-use std::fs::File;
-use std::io::prelude::*;
+use anyhow::Result;
+use std::io::Write;
 
+use crate::render::theme::{color, write_svg};
 use crate::utils::*;
 use newick::*;
 use svarog::*;
@@ -8,13 +9,14 @@ use svarog::*;
 const STEP_FORWARD: f32 = 20.;
 
 fn draw_tree(
-    svg: &mut SvgDrawing,
+    svg: &mut Group,
     t: &NewickTree,
     n: usize,
     xoffset: f32,
     yoffset: f32,
     render: &RenderSettings,
 ) -> f32 {
+    let font_size = render.dimensions.font_size;
     let mut y = yoffset;
     let leaves_count = t.leaves_of(n).len() as f32;
     let size = 10. * leaves_count.log10();
@@ -33,7 +35,7 @@ fn draw_tree(
                         (xoffset + step_forward, y),
                     ])
                     .style(|s| {
-                        s.stroke_color(StyleColor::RGB(0, 0, 0))
+                        s.stroke_color(color(&render.theme.foreground))
                             .stroke_width(thickness)
                             .fill_color(None)
                     });
@@ -46,7 +48,7 @@ fn draw_tree(
                         (xoffset + step_forward, y),
                     ])
                     .style(|s| {
-                        s.stroke_color(StyleColor::RGB(0, 0, 0))
+                        s.stroke_color(color(&render.theme.foreground))
                             .stroke_width(thickness)
                             .fill_color(None)
                     });
@@ -79,8 +81,8 @@ fn draw_tree(
             if let Some(annotation) = t.attrs(n).get(annotation) {
                 svg.text()
                     .pos(
-                        xoffset - FONT_SIZE,
-                        yoffset + FONT_SIZE + 1.1 * label_offset * FONT_SIZE,
+                        xoffset - font_size,
+                        yoffset + font_size + 1.1 * label_offset * font_size,
                     )
                     .text(annotation);
             }
@@ -89,12 +91,12 @@ fn draw_tree(
         let dcs = dcs.unwrap_or(0.0);
         svg.polygon()
             .from_pos_dims(xoffset - size / 2., yoffset - size / 2., size, size)
-            .style(|s| s.fill_color(Some(StyleColor::Percent(1.0 - dcs, dcs, 0.))));
+            .style(|s| s.fill_color(Some(render.theme.score(dcs))));
         if render.inner_tags {
             t.attrs(n).get("S").map(|name| {
                 svg.text()
-                    .pos(xoffset, yoffset - FONT_SIZE)
-                    .transform(|t| t.rotate_from(-30., xoffset, yoffset - FONT_SIZE))
+                    .pos(xoffset, yoffset - font_size)
+                    .transform(|t| t.rotate_from(-30., xoffset, yoffset - font_size))
                     .text(name)
             });
         }
@@ -104,10 +106,9 @@ fn draw_tree(
     }
 }
 
-pub fn render(t: &NewickTree, out_filename: &str, render: &RenderSettings) {
-    let mut svg = SvgDrawing::new();
+/// Render the topology of `t` as an SVG written to `out`
+pub fn render(t: &NewickTree, out: &mut impl Write, render: &RenderSettings) -> Result<()> {
+    let mut svg = Group::new();
     draw_tree(&mut svg, t, t.root(), 80., 80., render);
-    svg.auto_fit();
-    let mut out = File::create(out_filename).unwrap();
-    out.write_all(svg.render_svg().as_bytes()).unwrap();
+    write_svg(svg, &render.theme, out)
 }
@@ -0,0 +1,228 @@
+use anyhow::*;
+use colorsys::Rgb;
+use serde::Deserialize;
+use std::io::Write;
+use svarog::*;
+
+/// The colours and stroke widths of the SVG plots; colours are given as
+/// `#rrggbb` strings
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Theme {
+    /// The background of the plots, transparent if unset
+    pub background: Option<String>,
+    /// The branches, the texts and the links between genes
+    pub foreground: String,
+    /// The genes without a family color, and the leaves absent from the database
+    pub missing: String,
+    /// The alternating stripes behind the species of barcode plots
+    pub stripes: [String; 2],
+    /// The shading of the duplicated clades in flat plots
+    pub duplication: String,
+    /// The rows of the highlighted leaves
+    pub highlight: String,
+    /// The outline of the genes
+    pub gene_stroke: String,
+    /// The outline of the genes belonging to the family of their leaf
+    pub focus_stroke: String,
+    pub stroke_width: f32,
+    pub focus_stroke_width: f32,
+    /// The color of the duplications with a null consistency score
+    pub score_low: String,
+    /// The color of the duplications with a perfect consistency score
+    pub score_high: String,
+    /// The color of the species tree branches without duplications in
+    /// aggregated barcodes
+    pub heat_low: String,
+    /// The color of the species tree branches with the most duplications in
+    /// aggregated barcodes
+    pub heat_high: String,
+    /// Whether the families and species colors should be turned to greys
+    pub greyscale: bool,
+}
+impl Default for Theme {
+    fn default() -> Self {
+        Theme::light()
+    }
+}
+impl Theme {
+    pub const BUILTINS: [&'static str; 3] = ["light", "dark", "print"];
+
+    pub fn light() -> Self {
+        Theme {
+            background: None,
+            foreground: "#000000".into(),
+            missing: "#aaaaaa".into(),
+            stripes: ["#fcf7d9".into(), "#e5f8f9".into()],
+            duplication: "#8080ff".into(),
+            highlight: "#ff4d4d".into(),
+            gene_stroke: "#333333".into(),
+            focus_stroke: "#1a1a1a".into(),
+            stroke_width: 0.5,
+            focus_stroke_width: 2.,
+            score_low: "#ff0000".into(),
+            score_high: "#00ff00".into(),
+            heat_low: "#ffeda0".into(),
+            heat_high: "#bd0026".into(),
+            greyscale: false,
+        }
+    }
+
+    pub fn dark() -> Self {
+        Theme {
+            background: Some("#1e1e1e".into()),
+            foreground: "#e0e0e0".into(),
+            missing: "#606060".into(),
+            stripes: ["#2e2b1e".into(), "#1e2d2e".into()],
+            duplication: "#6060d0".into(),
+            highlight: "#ff6b6b".into(),
+            gene_stroke: "#b0b0b0".into(),
+            focus_stroke: "#ffffff".into(),
+            stroke_width: 0.5,
+            focus_stroke_width: 2.,
+            score_low: "#ff5555".into(),
+            score_high: "#55ff55".into(),
+            heat_low: "#3a3520".into(),
+            heat_high: "#ff4040".into(),
+            greyscale: false,
+        }
+    }
+
+    /// A greyscale theme, fit for black & white printing
+    pub fn print() -> Self {
+        Theme {
+            background: Some("#ffffff".into()),
+            foreground: "#000000".into(),
+            missing: "#d0d0d0".into(),
+            stripes: ["#f4f4f4".into(), "#e8e8e8".into()],
+            duplication: "#808080".into(),
+            highlight: "#a0a0a0".into(),
+            gene_stroke: "#000000".into(),
+            focus_stroke: "#000000".into(),
+            stroke_width: 0.5,
+            focus_stroke_width: 2.,
+            score_low: "#d0d0d0".into(),
+            score_high: "#000000".into(),
+            heat_low: "#e0e0e0".into(),
+            heat_high: "#000000".into(),
+            greyscale: true,
+        }
+    }
+
+    /// The built-in theme named `name`, if any
+    pub fn builtin(name: &str) -> Option<Self> {
+        match name {
+            "light" => Some(Theme::light()),
+            "dark" => Some(Theme::dark()),
+            "print" => Some(Theme::print()),
+            _ => None,
+        }
+    }
+
+    /// Read a theme from a TOML file; the missing entries are taken from the
+    /// light theme
+    pub fn from_file(filename: &str) -> Result<Self> {
+        let theme: Theme = toml::from_str(
+            &std::fs::read_to_string(filename)
+                .with_context(|| anyhow!("failed to read {}", filename))?,
+        )
+        .with_context(|| anyhow!("failed to parse theme from {}", filename))?;
+        for color in theme.background.iter().chain(theme.stripes.iter()).chain([
+            &theme.foreground,
+            &theme.missing,
+            &theme.duplication,
+            &theme.highlight,
+            &theme.gene_stroke,
+            &theme.focus_stroke,
+            &theme.score_low,
+            &theme.score_high,
+            &theme.heat_low,
+            &theme.heat_high,
+        ]) {
+            Rgb::from_hex_str(color)
+                .map_err(|_| anyhow!("{}: {} is not a #rrggbb color", filename, color))?;
+        }
+        Ok(theme)
+    }
+
+    /// Either a built-in theme, or one read from a file
+    pub fn from_name_or_file(name: &str) -> Result<Self> {
+        if let Some(theme) = Theme::builtin(name) {
+            Ok(theme)
+        } else if std::path::Path::new(name).exists() {
+            Theme::from_file(name)
+        } else {
+            bail!(
+                "{} is neither a theme file nor a built-in theme ({})",
+                name,
+                Theme::BUILTINS.join(", ")
+            )
+        }
+    }
+
+    /// The color of a duplication with the given consistency score
+    pub fn score(&self, score: f32) -> StyleColor {
+        mix(&self.score_low, &self.score_high, score)
+    }
+
+    /// The color of a species tree branch in aggregated barcodes, `heat`
+    /// ranging from 0 to 1
+    pub fn heat(&self, heat: f32) -> StyleColor {
+        mix(&self.heat_low, &self.heat_high, heat)
+    }
+
+    /// Adapt a family or species color to the theme
+    pub fn paint(&self, color: StyleColor) -> StyleColor {
+        if !self.greyscale {
+            return color;
+        }
+        let (r, g, b) = match &color {
+            StyleColor::String(s) => {
+                let Result::Ok(rgb) = Rgb::from_hex_str(s) else {
+                    return color;
+                };
+                (
+                    rgb.red() as f32 / 255.,
+                    rgb.green() as f32 / 255.,
+                    rgb.blue() as f32 / 255.,
+                )
+            }
+            _ => color.to_percent(),
+        };
+        let luminance = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+        StyleColor::Percent(luminance, luminance, luminance)
+    }
+}
+
+/// Interpolate between the `low` and `high` colors of a theme
+fn mix(low: &str, high: &str, f: f32) -> StyleColor {
+    let [low, high] = [low, high].map(|c| Rgb::from_hex_str(c).unwrap_or_default());
+    let mix = |l: f64, h: f64| ((l * (1. - f as f64) + h * f as f64) / 255.) as f32;
+    StyleColor::Percent(
+        mix(low.red(), high.red()),
+        mix(low.green(), high.green()),
+        mix(low.blue(), high.blue()),
+    )
+}
+
+/// Convert a color of a theme to its SVG counterpart
+pub fn color(c: &str) -> StyleColor {
+    StyleColor::String(c.to_owned())
+}
+
+/// Write `content` to `out` as a standalone SVG, laid on the background of
+/// `theme`
+pub fn write_svg(mut content: Group, theme: &Theme, out: &mut impl Write) -> Result<()> {
+    let mut svg = SvgDrawing::new();
+    if let Some(background) = theme.background.as_ref() {
+        let bbox = content.bbox();
+        svg.polygon()
+            .from_corners((0., 0.), (bbox.x2, bbox.y2))
+            .style(|s| s.fill_color(Some(color(background))));
+    }
+    content.style(&|s| s.fill_color(Some(color(&theme.foreground))));
+    svg.push(Box::new(content));
+    svg.auto_fit();
+    out.write_all(svg.render_svg().as_bytes())?;
+    Ok(())
+}
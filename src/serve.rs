@@ -0,0 +1,384 @@
+use anyhow::*;
+use colored::Colorize;
+use log::*;
+use newick::*;
+use rusqlite::Connection;
+use std::collections::{HashMap, VecDeque};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+use syntesuite::genebook::GeneBook;
+
+use crate::db::{self, NeighbourCache};
+use crate::render;
+use crate::utils::*;
+
+/// Everything required to answer the requests about a tree, computed on its
+/// first request
+struct TreeState {
+    tree: NewickTree,
+    data: String,
+    genes: GeneCache,
+    neighbours: NeighbourCache,
+    colormap: ColorMap,
+}
+
+/// How many trees are kept in memory at most
+const MAX_LOADED_TREES: usize = 32;
+/// The extensions of the files considered as trees
+const TREE_EXTENSIONS: &[&str] = &["nhx", "nwk", "newick", "nh", "tree", "tre"];
+/// How long a connection may stay idle before being dropped
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
+/// The maximal size of the request line and headers
+const MAX_REQUEST_SIZE: u64 = 64 * 1024;
+
+/// The trees loaded so far
+#[derive(Default)]
+struct Cache {
+    states: HashMap<String, Arc<TreeState>>,
+    /// The trees in `states`, from the least to the most recently loaded
+    loaded: VecDeque<String>,
+}
+
+pub struct Server {
+    trees: HashMap<String, String>,
+    db_file: String,
+    book: GeneBook,
+    conn: Mutex<Connection>,
+    id_column: String,
+    ids: IdMapping,
+    missing: MissingPolicy,
+    species_tree: Option<NewickTree>,
+    render: RenderSettings,
+    cache: Mutex<Cache>,
+}
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: String,
+}
+impl Response {
+    fn ok(content_type: &'static str, body: String) -> Self {
+        Response {
+            status: "200 OK",
+            content_type,
+            body,
+        }
+    }
+
+    fn not_found(what: &str) -> Self {
+        Response {
+            status: "404 Not Found",
+            content_type: "text/plain; charset=utf-8",
+            body: format!("{} not found", what),
+        }
+    }
+
+    /// The details of `e` are logged, but only its summary is sent back
+    fn error(e: &Error) -> Self {
+        warn!("{:?}", e);
+        Response {
+            status: "500 Internal Server Error",
+            content_type: "text/plain; charset=utf-8",
+            body: e.to_string(),
+        }
+    }
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut r = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            if let Some(b) = s
+                .get(i + 1..i + 3)
+                .and_then(|h| u8::from_str_radix(h, 16).ok())
+            {
+                r.push(b);
+                i += 3;
+                continue;
+            }
+        }
+        r.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&r).into_owned()
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn url_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| {
+            if b.is_ascii_alphanumeric() || b"-_.~".contains(&b) {
+                (b as char).to_string()
+            } else {
+                format!("%{:02X}", b)
+            }
+        })
+        .collect()
+}
+
+impl Server {
+    /// Open the database and index the trees found in `trees_dir`; `missing`
+    /// is applied to the leaves absent from the database
+    pub fn new(
+        db_file: &str,
+        trees_dir: &str,
+        id_column: &str,
+        ids: IdMapping,
+        missing: MissingPolicy,
+        species_tree: Option<NewickTree>,
+        render: RenderSettings,
+    ) -> Result<Self> {
+        let conn = db::connect(db_file)?;
+        let book = GeneBook::inline(db_file, WINDOW, id_column).map_err(|e| anyhow!(e))?;
+        let trees = db::list_files(&[trees_dir.to_owned()])?
+            .into_iter()
+            .filter(|path| {
+                let path = std::path::Path::new(path);
+                path.is_file()
+                    && path
+                        .extension()
+                        .and_then(|e| e.to_str())
+                        .is_some_and(|e| TREE_EXTENSIONS.contains(&e.to_lowercase().as_str()))
+            })
+            .map(|path| {
+                let name = std::path::Path::new(&path)
+                    .strip_prefix(trees_dir)
+                    .map(|p| p.to_string_lossy().into_owned())
+                    .unwrap_or_else(|_| path.clone());
+                (name, path)
+            })
+            .collect::<HashMap<_, _>>();
+        info!("Serving {} trees from {}", trees.len(), trees_dir.bold());
+
+        Ok(Server {
+            trees,
+            db_file: db_file.to_owned(),
+            book,
+            conn: Mutex::new(conn),
+            id_column: id_column.to_owned(),
+            ids,
+            missing,
+            species_tree,
+            render,
+            cache: Mutex::new(Cache::default()),
+        })
+    }
+
+    // The cache is consistent between any two operations, even if a thread
+    // panicked while holding it
+    fn cache(&self) -> MutexGuard<'_, Cache> {
+        self.cache.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn state(&self, name: &str) -> Result<Option<Arc<TreeState>>> {
+        if let Some(state) = self.cache().states.get(name) {
+            return Ok(Some(state.clone()));
+        }
+        let Some(path) = self.trees.get(name) else {
+            return Ok(None);
+        };
+        // Trees are loaded without holding the cache, so that the requests
+        // about the other trees are not delayed
+        debug!("Loading {}", path);
+        let mut t = newick::one_from_filename(path)
+            .with_context(|| anyhow!("failed to read `{}`", path))?;
+        db::apply_missing_policy(
+            &mut t,
+            &self.db_file,
+            &self.id_column,
+            &self.ids,
+            self.missing,
+        )?;
+        let (genes, _) = make_oriented_genes_cache_from_book(&t, &self.book, &self.ids)?;
+        let neighbours = db::make_neighbours_cache(
+            &self.conn.lock().unwrap_or_else(PoisonError::into_inner),
+            &self.id_column,
+            &genes,
+        )?;
+        let colormap = make_colormap(&t, &genes);
+        let data = render::html::data(&t, &genes, &neighbours, &colormap, true);
+        let state = Arc::new(TreeState {
+            tree: t,
+            data,
+            genes,
+            neighbours,
+            colormap,
+        });
+
+        let mut cache = self.cache();
+        // Another request may have loaded it in the meantime
+        if !cache.states.contains_key(name) {
+            cache.states.insert(name.to_owned(), state.clone());
+            cache.loaded.push_back(name.to_owned());
+            if cache.loaded.len() > MAX_LOADED_TREES {
+                let evicted = cache.loaded.pop_front().unwrap();
+                cache.states.remove(&evicted);
+            }
+        }
+        Ok(Some(state))
+    }
+
+    fn index(&self) -> String {
+        let mut names = self.trees.keys().collect::<Vec<_>>();
+        names.sort();
+        format!(
+            "<html><head><meta charset=\"UTF-8\"><title>genominicus</title></head><body><h2>Gene trees</h2><ul>{}</ul></body></html>",
+            names
+                .iter()
+                .map(|n| format!(
+                    "<li><a href=\"/tree/{}\">{}</a></li>",
+                    url_encode(n),
+                    html_escape(n)
+                ))
+                .collect::<String>()
+        )
+    }
+
+    fn route(&self, path: &str) -> Result<Response> {
+        let path = path.split('?').next().unwrap_or_default();
+        let segments = path
+            .trim_matches('/')
+            .split('/')
+            .map(percent_decode)
+            .collect::<Vec<_>>();
+        let segments = segments.iter().map(String::as_str).collect::<Vec<_>>();
+
+        Ok(match segments.as_slice() {
+            [""] => Response::ok("text/html; charset=utf-8", self.index()),
+            ["tree", name] => {
+                let api = format!("/api/tree/{}", url_encode(name));
+                let Some(state) = self.state(name)? else {
+                    return Ok(Response::not_found(name));
+                };
+                Response::ok(
+                    "text/html; charset=utf-8",
+                    render::html::page(
+                        name,
+                        &state.data,
+                        self.species_tree.as_ref(),
+                        &self.render,
+                        Some(&api),
+                    )?,
+                )
+            }
+            ["api", "trees"] => {
+                let mut names = self.trees.keys().collect::<Vec<_>>();
+                names.sort();
+                Response::ok("application/json", serde_json::to_string(&names)?)
+            }
+            ["api", "tree", name] => match self.state(name)? {
+                Some(state) => Response::ok("application/json", state.data.clone()),
+                None => Response::not_found(name),
+            },
+            ["api", "tree", name, "landscape", leaf] => match self.state(name)? {
+                Some(state) => {
+                    render::html::landscape(leaf, &state.genes, &state.neighbours, &state.colormap)
+                        .map(|l| Response::ok("application/json", l))
+                        .unwrap_or_else(|| Response::not_found(leaf))
+                }
+                None => Response::not_found(name),
+            },
+            ["api", "tree", name, "cluster", node] => match self.state(name)? {
+                // Nodes without any known leaf have no alignment
+                Some(state) => match node
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|&node| state.tree.get(node).is_ok())
+                {
+                    Some(node) => Response::ok(
+                        "application/json",
+                        render::html::clustered(&state.tree, node, &state.genes, &state.colormap)
+                            .unwrap_or_else(|| "null".to_owned()),
+                    ),
+                    None => Response::not_found(node),
+                },
+                None => Response::not_found(name),
+            },
+            _ => Response::not_found(path),
+        })
+    }
+
+    fn handle(&self, stream: TcpStream) -> Result<()> {
+        // Idle connections, such as the ones opened ahead by browsers, must
+        // not be kept forever
+        stream.set_read_timeout(Some(CONNECTION_TIMEOUT))?;
+        stream.set_write_timeout(Some(CONNECTION_TIMEOUT))?;
+        let mut reader = BufReader::new((&stream).take(MAX_REQUEST_SIZE));
+        let mut request = String::new();
+        if reader.read_line(&mut request)? == 0 {
+            return Ok(());
+        }
+        // Headers are not used
+        loop {
+            let mut header = String::new();
+            if reader.read_line(&mut header)? == 0 {
+                bail!("incomplete or oversized request headers");
+            }
+            if header.trim().is_empty() {
+                break;
+            }
+        }
+
+        let mut fields = request.split_whitespace();
+        let response = match (fields.next(), fields.next()) {
+            (Some("GET"), Some(path)) => {
+                debug!("GET {}", path);
+                self.route(path).unwrap_or_else(|e| Response::error(&e))
+            }
+            _ => Response {
+                status: "405 Method Not Allowed",
+                content_type: "text/plain; charset=utf-8",
+                body: String::new(),
+            },
+        };
+
+        let mut stream = stream;
+        write!(
+            stream,
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            response.status,
+            response.content_type,
+            response.body.len()
+        )?;
+        stream.write_all(response.body.as_bytes())?;
+        stream.flush()?;
+        Ok(())
+    }
+
+    /// Serve the trees on `localhost:port` until interrupted, each connection
+    /// in its own thread
+    pub fn run(self, port: u16) -> Result<()> {
+        let listener = TcpListener::bind(("127.0.0.1", port))
+            .with_context(|| anyhow!("failed to bind to port {}", port))?;
+        eprintln!(
+            "Serving on {}",
+            format!("http://localhost:{}/", port).bold()
+        );
+        let server = Arc::new(self);
+        for stream in listener.incoming() {
+            match stream {
+                Result::Ok(stream) => {
+                    let server = server.clone();
+                    std::thread::spawn(move || {
+                        if let Err(e) = server.handle(stream) {
+                            warn!("{:?}", e);
+                        }
+                    });
+                }
+                Err(e) => warn!("{}", e),
+            }
+        }
+        Ok(())
+    }
+}
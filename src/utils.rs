@@ -1,19 +1,20 @@
 #![allow(dead_code)]
 use anyhow::*;
+use colored::Colorize;
 use colorsys::{Hsl, Rgb};
 use newick::*;
-use once_cell::sync::OnceCell;
 use palette::*;
 use petname::Generator;
 use rand::prelude::*;
+use regex::Regex;
+use rusqlite::OptionalExtension;
+use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::iter::FromIterator;
 use svarog::*;
 use syntesuite::genebook::{FamilyID, Gene, GeneBook};
 
-static ANCESTRAL_QUERY: OnceCell<String> = OnceCell::new();
-const LEFTS_QUERY: &str = "select ancestral, direction from genomes where species=? and chr=? and start<? order by start desc limit ?";
-const RIGHTS_QUERY: &str = "select ancestral, direction from genomes where species=? and chr=? and start>? order by start asc limit ?";
+use crate::render::theme::Theme;
 
 pub const WINDOW: usize = 15;
 pub const GENE_WIDTH: f32 = 15.;
@@ -21,7 +22,7 @@ pub const GENE_SPACING: f32 = 5.;
 pub const BRANCH_WIDTH: f32 = 20.;
 pub const FONT_SIZE: f32 = 10.;
 
-#[derive(Copy, Clone, Hash)]
+#[derive(Copy, Clone, Hash, PartialEq, Eq)]
 pub enum PoaElt {
     Gene(FamilyID),
     Marker,
@@ -42,18 +43,81 @@ impl std::fmt::Display for PoaElt {
         )
     }
 }
-impl std::cmp::PartialEq for PoaElt {
-    fn eq(&self, other: &Self) -> bool {
-        match (self, other) {
-            (PoaElt::Gene(x), PoaElt::Gene(y)) => x.eq(y),
-            (PoaElt::Marker, PoaElt::Marker)
-            | (PoaElt::Indel, PoaElt::Indel)
-            | (PoaElt::Empty, PoaElt::Empty) => true,
-            _ => false,
+
+/// What to do with the leaves of a tree that can not be found in the database
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MissingPolicy {
+    /// Abort the process
+    Fail,
+    /// Remove them from the tree
+    Skip,
+    /// Render them as empty placeholders
+    Placeholder,
+}
+impl TryFrom<&str> for MissingPolicy {
+    type Error = anyhow::Error;
+
+    fn try_from(s: &str) -> Result<Self> {
+        match s {
+            "fail" => Ok(MissingPolicy::Fail),
+            "skip" => Ok(MissingPolicy::Skip),
+            "placeholder" => Ok(MissingPolicy::Placeholder),
+            _ => bail!("unknown policy for missing leaves: {}", s),
+        }
+    }
+}
+
+/// URL templates for the external links of the HTML output; `{gene}`,
+/// `{species}`, `{chr}` and `{family}` are replaced by the values of each gene,
+/// and a missing template disables the corresponding link.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LinkTemplates {
+    pub gene: Option<String>,
+    pub locus: Option<String>,
+}
+impl Default for LinkTemplates {
+    fn default() -> Self {
+        LinkTemplates {
+            gene: Some("https://www.ensembl.org/Multi/Search/Results?q={gene};site=ensembl".into()),
+            locus: Some("https://www.genomicus.bio.ens.psl.eu/genomicus-104.02/cgi-bin/search.pl?query={gene}&view=default&nocache=$random".into()),
+        }
+    }
+}
+impl LinkTemplates {
+    pub fn from_file(filename: &str) -> Result<Self> {
+        let content = std::fs::read_to_string(filename)
+            .with_context(|| anyhow!("failed to read {}", filename))?;
+        serde_json::from_str(&content)
+            .with_context(|| anyhow!("failed to parse link templates from {}", filename))
+    }
+
+    pub fn none() -> Self {
+        LinkTemplates {
+            gene: None,
+            locus: None,
+        }
+    }
+}
+
+/// The sizes of the elements of the SVG plots
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Dimensions {
+    pub gene_width: f32,
+    pub gene_spacing: f32,
+    pub branch_width: f32,
+    pub font_size: f32,
+}
+impl Default for Dimensions {
+    fn default() -> Self {
+        Dimensions {
+            gene_width: GENE_WIDTH,
+            gene_spacing: GENE_SPACING,
+            branch_width: BRANCH_WIDTH,
+            font_size: FONT_SIZE,
         }
     }
 }
-impl Eq for PoaElt {}
 
 #[derive(Debug, Default)]
 pub struct RenderSettings {
@@ -61,16 +125,88 @@ pub struct RenderSettings {
     pub node_annotations: Vec<String>,
     pub links: bool,
     pub duplication_ids: bool,
+    pub highlighted_leaves: HashSet<String>,
+    pub link_templates: LinkTemplates,
+    pub dimensions: Dimensions,
+    pub theme: Theme,
 }
 
 pub type GeneCache = HashMap<String, Gene>;
 pub type ColorMap = HashMap<usize, StyleColor>;
 pub type PetnameMap = HashMap<usize, String>;
 
-fn jaccard<T: std::hash::Hash + Eq>(x: &HashSet<T>, y: &HashSet<T>) -> f32 {
+/// Create `filename` for buffered writing
+pub fn create(filename: &str) -> Result<std::io::BufWriter<std::fs::File>> {
+    std::fs::File::create(filename)
+        .map(std::io::BufWriter::new)
+        .with_context(|| anyhow!("failed to create {}", filename.bold().yellow()))
+}
+
+/// Open `filename` for writing, or the standard output if it is `None`
+pub fn output(filename: Option<&str>) -> Result<Box<dyn std::io::Write>> {
+    Ok(if let Some(filename) = filename {
+        Box::new(create(filename)?)
+    } else {
+        Box::new(std::io::BufWriter::new(std::io::stdout().lock()))
+    })
+}
+
+/// The path, without extension, the plots of the tree `filename` are written
+/// to: `out` if set, or else `filename` itself
+pub fn plot_stem(filename: &str, out: Option<&str>) -> Result<String> {
+    let mut stem = std::path::PathBuf::from(out.unwrap_or(filename));
+    let name = stem
+        .file_stem()
+        .with_context(|| {
+            anyhow!(
+                "invalid file name: {}",
+                stem.display().to_string().bold().yellow()
+            )
+        })?
+        .to_owned();
+    stem.set_file_name(name);
+    Ok(stem.to_string_lossy().into_owned())
+}
+
+pub fn jaccard<T: std::hash::Hash + Eq>(x: &HashSet<T>, y: &HashSet<T>) -> f32 {
     x.intersection(y).count() as f32 / x.union(y).count() as f32
 }
 
+/// The families found in the landscape of `leaf`, if it is in `genes`
+pub fn landscape_set(
+    genes: &GeneCache,
+    tree: &NewickTree,
+    leaf: usize,
+) -> Option<HashSet<FamilyID>> {
+    tree.name(leaf).and_then(|name| genes.get(name)).map(|g| {
+        g.left_landscape
+            .iter()
+            .chain(g.right_landscape.iter())
+            .map(|tg| tg.family)
+            .collect()
+    })
+}
+
+/// A human-readable description of a clade, _i.e._ its name, its species, or
+/// the list of its leaves
+pub fn clade_name(tree: &NewickTree, n: usize) -> String {
+    tree.name(n)
+        .cloned()
+        .or_else(|| {
+            tree.attrs(n)
+                .get("S")
+                .filter(|_| !tree[n].is_leaf())
+                .map(|s| format!("{}:{}", s, n))
+        })
+        .unwrap_or_else(|| {
+            tree.leaves_of(n)
+                .iter()
+                .filter_map(|l| tree.name(*l).cloned())
+                .collect::<Vec<_>>()
+                .join(",")
+        })
+}
+
 // Creates a color for a string while trying to ensure it remains readable
 pub fn name2color<S: AsRef<str>>(name: S) -> StyleColor {
     let bytes: [u8; 16] = md5::compute(name.as_ref().as_bytes()).into();
@@ -95,11 +231,7 @@ pub fn gene2color(id: &[u8]) -> StyleColor {
     StyleColor::Percent(r, g, b)
 }
 
-pub fn set_reference(reference: &str) {
-    ANCESTRAL_QUERY.set(format!(
-        "select ancestral, species, chr, start, direction, left_tail_names, right_tail_names from genomes where {}=?", reference)).unwrap();
-}
-
+/// Give a random pet name to each family found in the landscapes of `tree`
 pub fn make_petnamemap(tree: &NewickTree, genes: &GeneCache) -> PetnameMap {
     let mut petmap = PetnameMap::new();
     for l in tree.leaves() {
@@ -124,6 +256,7 @@ pub fn make_petnamemap(tree: &NewickTree, genes: &GeneCache) -> PetnameMap {
     petmap
 }
 
+/// Color each family found in the landscapes of `tree` after its ID
 pub fn make_colormap(tree: &NewickTree, genes: &GeneCache) -> ColorMap {
     let mut colormap = ColorMap::new();
     for l in tree.leaves() {
@@ -142,6 +275,9 @@ pub fn make_colormap(tree: &NewickTree, genes: &GeneCache) -> ColorMap {
     colormap
 }
 
+/// Color the families found in the landscapes below each duplication of
+/// `tree` with a gradient following the landscape of its most representative
+/// leaf; if `colorize_all`, the remaining families are colored after their ID.
 pub fn make_colormap_per_duplication(
     tree: &NewickTree,
     genes: &GeneCache,
@@ -261,13 +397,307 @@ pub fn make_colormap_per_duplication(
     colormap
 }
 
+/// Maps the leaf names of the gene trees to the gene IDs stored in the database
+#[derive(Debug, Default)]
+pub struct IdMapping {
+    /// A regex extracting the ID from the leaf name in its `id` capture group
+    pattern: Option<Regex>,
+    /// A translation table from leaf names or extracted IDs to database IDs
+    table: HashMap<String, String>,
+}
+impl IdMapping {
+    pub fn new(pattern: Option<&str>, table_file: Option<&str>) -> Result<Self> {
+        let pattern = pattern
+            .map(|p| {
+                let re = Regex::new(p).with_context(|| anyhow!("{} is not a valid regex", p))?;
+                if !re.capture_names().any(|n| n == Some("id")) {
+                    bail!("capture group `id` missing in {}", p)
+                }
+                Ok(re)
+            })
+            .transpose()?;
+        let table = table_file
+            .map(|f| -> Result<HashMap<String, String>> {
+                let mut table = HashMap::new();
+                for (i, l) in std::fs::read_to_string(f)
+                    .with_context(|| anyhow!("failed to read {}", f))?
+                    .lines()
+                    .enumerate()
+                {
+                    let l = l.trim();
+                    if l.is_empty() || l.starts_with('#') {
+                        continue;
+                    }
+                    let mut columns = l.split_whitespace();
+                    match (columns.next(), columns.next()) {
+                        (Some(from), Some(to)) => {
+                            table.insert(from.to_owned(), to.to_owned());
+                        }
+                        _ => bail!("{}:{}: expected two columns", f, i + 1),
+                    }
+                }
+                Ok(table)
+            })
+            .transpose()?
+            .unwrap_or_default();
+        Ok(IdMapping { pattern, table })
+    }
+
+    /// The database ID corresponding to the given leaf name
+    pub fn db_id(&self, leaf: &str) -> String {
+        if let Some(id) = self.table.get(leaf) {
+            return id.to_owned();
+        }
+        let extracted = self
+            .pattern
+            .as_ref()
+            .and_then(|re| re.captures(leaf))
+            .and_then(|c| c.name("id"))
+            .map(|m| m.as_str())
+            .unwrap_or(leaf);
+        self.table
+            .get(extracted)
+            .cloned()
+            .unwrap_or_else(|| extracted.to_owned())
+    }
+}
+
+/// Compile a regex extracting the species from the leaf names in its
+/// `species` capture group
+pub fn leaf_species_pattern(pattern: &str) -> Result<Regex> {
+    let re = Regex::new(pattern).with_context(|| anyhow!("{} is not a valid regex", pattern))?;
+    if !re.capture_names().any(|n| n == Some("species")) {
+        bail!("capture group `species` missing in {}", pattern)
+    }
+    Ok(re)
+}
+
+/// The species of the leaves of `t`, either from their `S` annotation or
+/// extracted from their name by `pattern`; `None` if some are unknown
+pub fn leaf_species(t: &NewickTree, pattern: Option<&Regex>) -> Option<HashSet<String>> {
+    t.leaves()
+        .map(|l| {
+            t.attrs(l).get("S").cloned().or_else(|| {
+                pattern
+                    .zip(t.name(l))
+                    .and_then(|(re, name)| re.captures(name))
+                    .and_then(|c| c.name("species"))
+                    .map(|m| m.as_str().to_owned())
+            })
+        })
+        .collect()
+}
+
+/// Whether some leaves of `t` lack a species (`S`) annotation
+pub fn lacks_species(t: &NewickTree) -> bool {
+    t.leaves().any(|l| !t.attrs(l).contains_key("S"))
+}
+
+/// Fill in the missing species (`S`) annotations of `t`. The species of a
+/// leaf is looked up in `genes`, or else extracted from its name by `pattern`;
+/// the species of an inner node is the MRCA in `species_tree` of the species
+/// of its leaves.
+pub fn infer_species(
+    t: &mut NewickTree,
+    genes: Option<&GeneCache>,
+    pattern: Option<&Regex>,
+    species_tree: Option<&NewickTree>,
+) {
+    for l in t.leaves().collect::<Vec<_>>() {
+        if t.attrs(l).contains_key("S") {
+            continue;
+        }
+        let Some(name) = t.name(l) else {
+            continue;
+        };
+        let species = genes
+            .and_then(|genes| genes.get(name))
+            .map(|gene| gene.species.to_owned())
+            .or_else(|| {
+                pattern
+                    .and_then(|re| re.captures(name))
+                    .and_then(|c| c.name("species"))
+                    .map(|m| m.as_str().to_owned())
+            });
+        if let Some(species) = species {
+            t.attrs_mut(l).insert("S".into(), species);
+        }
+    }
+
+    let Some(species_tree) = species_tree else {
+        return;
+    };
+    let species_nodes = species_tree
+        .leaves()
+        .filter_map(|s| species_tree.name(s).map(|name| (name.as_str(), s)))
+        .collect::<HashMap<_, _>>();
+    // The MRCA in the species tree of the species under each node, if all of
+    // them are known; children are visited before their parent
+    let mut mrcas = HashMap::<usize, Option<usize>>::new();
+    let root = t.root();
+    for n in std::iter::once(root)
+        .chain(t.descendants(root))
+        .collect::<Vec<_>>()
+        .into_iter()
+        .rev()
+    {
+        let mrca = if t[n].is_leaf() {
+            t.attrs(n)
+                .get("S")
+                .and_then(|species| species_nodes.get(species.as_str()).copied())
+        } else {
+            t[n].children()
+                .iter()
+                .map(|c| mrcas[c])
+                .collect::<Option<Vec<_>>>()
+                .and_then(|species| species_tree.mrca(species).ok())
+        };
+        mrcas.insert(n, mrca);
+        if !t[n].is_leaf() && !t.attrs(n).contains_key("S") {
+            if let Some(name) = mrca.and_then(|mrca| species_tree.name(mrca).cloned()) {
+                t.attrs_mut(n).insert("S".into(), name);
+            }
+        }
+    }
+}
+
+/// Fill in the missing species (`S`) annotations of `t` as `infer_species`
+/// does, the species of the leaves being looked up in `db_file` if set
+pub fn complete_species(
+    t: &mut NewickTree,
+    db_file: &str,
+    id_column: &str,
+    ids: &IdMapping,
+    pattern: Option<&Regex>,
+    species_tree: Option<&NewickTree>,
+) -> Result<()> {
+    if lacks_species(t) && !db_file.is_empty() {
+        let conn = crate::db::connect(db_file)?;
+        let mut species_of =
+            conn.prepare(&format!("SELECT species FROM genomes WHERE {id_column}=?"))?;
+        for l in t.leaves().collect::<Vec<_>>() {
+            if t.attrs(l).contains_key("S") {
+                continue;
+            }
+            let Some(name) = t.name(l) else {
+                continue;
+            };
+            let species = species_of
+                .query_row([ids.db_id(name)], |r| r.get::<_, String>(0))
+                .optional()?;
+            if let Some(species) = species {
+                t.attrs_mut(l).insert("S".into(), species);
+            }
+        }
+    }
+    infer_species(t, None, pattern, species_tree);
+    Ok(())
+}
+
+/// Build the gene cache of `t`, with the landscapes of related leaves
+/// oriented alike
 pub fn make_genes_cache(
     t: &NewickTree,
     db_file: &str,
     id_column: &str,
-) -> Result<HashMap<String, Gene>> {
-    fn reorder_tails(tree: &NewickTree, node: usize, genes: &mut GeneBook) {
-        fn reorder_leaves(t: &NewickTree, leave_nodes: &[usize], genes: &mut GeneBook) {
+    ids: &IdMapping,
+) -> Result<GeneCache> {
+    make_genes_cache_with_placeholders(t, db_file, id_column, ids, &HashSet::new())
+}
+
+/// As `make_genes_cache`, but tolerate the `placeholders` leaves being absent
+/// from the database; they are then left out of the cache
+pub fn make_genes_cache_with_placeholders(
+    t: &NewickTree,
+    db_file: &str,
+    id_column: &str,
+    ids: &IdMapping,
+    placeholders: &HashSet<String>,
+) -> Result<GeneCache> {
+    let db_ids = leaf_db_ids(t, ids);
+    let gene_book = GeneBook::cached(
+        db_file,
+        WINDOW,
+        id_column,
+        &db_ids.values().collect::<Vec<_>>(),
+    )
+    .map_err(|e| anyhow!(e))?;
+    orient_genes(t, gene_book, &db_ids, placeholders).map(|(genes, _)| genes)
+}
+
+/// Build the gene cache of `t`, and also return the names of the leaves whose
+/// landscape has been flipped to match the orientation of their relatives.
+/// The cache is indexed by the leaf names of `t`, whereas the genes are looked
+/// up in the database by their ID as mapped by `ids`.
+pub fn make_oriented_genes_cache(
+    t: &NewickTree,
+    db_file: &str,
+    id_column: &str,
+    ids: &IdMapping,
+) -> Result<(GeneCache, HashSet<String>)> {
+    let db_ids = leaf_db_ids(t, ids);
+    let gene_book = GeneBook::cached(
+        db_file,
+        WINDOW,
+        id_column,
+        &db_ids.values().collect::<Vec<_>>(),
+    )
+    .map_err(|e| anyhow!(e))?;
+    orient_genes(t, gene_book, &db_ids, &HashSet::new())
+}
+
+/// As `make_oriented_genes_cache`, but pick the genes from an already opened
+/// gene book instead of the database; the leaves absent from the book are
+/// left out of the cache
+pub fn make_oriented_genes_cache_from_book(
+    t: &NewickTree,
+    book: &GeneBook,
+    ids: &IdMapping,
+) -> Result<(GeneCache, HashSet<String>)> {
+    let db_ids = leaf_db_ids(t, ids);
+    let mut genes = HashMap::new();
+    let mut placeholders = HashSet::new();
+    for (&leaf, id) in db_ids.iter() {
+        if let Result::Ok(g) = book.get(id) {
+            genes.insert(id.to_owned(), g);
+        } else {
+            placeholders.insert(leaf.to_owned());
+        }
+    }
+    let gene_book = GeneBook::Cached {
+        genes,
+        species: Vec::new(),
+    };
+    orient_genes(t, gene_book, &db_ids, &placeholders)
+}
+
+fn leaf_db_ids<'a>(t: &'a NewickTree, ids: &IdMapping) -> HashMap<&'a String, String> {
+    t.leaves()
+        .filter_map(|n| t.name(n))
+        .map(|l| (l, ids.db_id(l)))
+        .collect()
+}
+
+fn orient_genes(
+    t: &NewickTree,
+    mut gene_book: GeneBook,
+    db_ids: &HashMap<&String, String>,
+    placeholders: &HashSet<String>,
+) -> Result<(GeneCache, HashSet<String>)> {
+    fn reorder_tails(
+        tree: &NewickTree,
+        node: usize,
+        genes: &mut GeneBook,
+        db_ids: &HashMap<&String, String>,
+        flipped: &mut HashSet<String>,
+    ) {
+        fn reorder_leaves(
+            t: &NewickTree,
+            leave_nodes: &[usize],
+            genes: &mut GeneBook,
+            db_ids: &HashMap<&String, String>,
+            flipped: &mut HashSet<String>,
+        ) {
             if leave_nodes.len() < 2 {
                 return;
             }
@@ -275,7 +705,7 @@ pub fn make_genes_cache(
             let tails = leave_nodes
                 .iter()
                 .filter_map(|l| t.name(*l))
-                .filter_map(|name| genes.get(name).ok())
+                .filter_map(|name| genes.get(&db_ids[name]).ok())
                 .map(|g| {
                     (
                         g.strand,
@@ -290,6 +720,9 @@ pub fn make_genes_cache(
                     )
                 })
                 .collect::<Vec<_>>();
+            if tails.is_empty() {
+                return;
+            }
             let tailsets = tails
                 .iter()
                 .map(|t| {
@@ -325,7 +758,7 @@ pub fn make_genes_cache(
             let ref_right_tail: HashSet<_> = HashSet::from_iter(tails[ref_id].2.iter().cloned());
 
             for l_name in leave_nodes.iter().filter_map(|l| t.name(*l)) {
-                if let Result::Ok(gene) = genes.get_mut(l_name) {
+                if let Result::Ok(gene) = genes.get_mut(&db_ids[l_name]) {
                     let left_tail: HashSet<FamilyID> =
                         HashSet::from_iter(gene.left_landscape.iter().map(|tg| tg.family));
                     let right_tail: HashSet<FamilyID> =
@@ -341,6 +774,10 @@ pub fn make_genes_cache(
                         gene.left_landscape.reverse();
                         gene.right_landscape.reverse();
                         gene.strand.reverse();
+                        // A gene flipped twice is back in its original orientation
+                        if !flipped.remove(l_name) {
+                            flipped.insert(l_name.to_owned());
+                        }
                     }
                 }
             }
@@ -353,31 +790,134 @@ pub fn make_genes_cache(
                 .filter(|c| tree[**c].is_leaf())
                 .cloned()
                 .collect::<Vec<_>>();
-            reorder_leaves(tree, &members, genes);
+            reorder_leaves(tree, &members, genes, db_ids, flipped);
 
             for c in children.iter().filter(|c| !tree[**c].is_leaf()) {
-                reorder_leaves(tree, &tree.leaves_of(*c), genes);
+                reorder_leaves(tree, &tree.leaves_of(*c), genes, db_ids, flipped);
             }
         }
 
         for c in tree[node].children().iter() {
-            reorder_tails(tree, *c, genes);
+            reorder_tails(tree, *c, genes, db_ids, flipped);
         }
     }
 
     let leaves = t.leaves().filter_map(|n| t.name(n)).collect::<Vec<_>>();
-    let mut gene_book =
-        GeneBook::cached(db_file, WINDOW, id_column, &leaves).map_err(|e| anyhow!(e))?;
-    reorder_tails(t, t.root(), &mut gene_book);
+    let mut flipped = HashSet::new();
+    reorder_tails(t, t.root(), &mut gene_book, db_ids, &mut flipped);
     let r = leaves
         .into_iter()
+        .filter(|g| !placeholders.contains(*g))
         .map(|g| {
-            gene_book.get(g).map(|mut gene| {
+            gene_book.get(&db_ids[g]).map(|mut gene| {
                 // XXX: left tails are drawn right to left, so they must be reversed
                 gene.left_landscape.reverse();
                 (g.to_owned(), gene)
             })
         })
         .collect::<Result<HashMap<_, _>>>()?;
-    Ok(r)
+    Ok((r, flipped))
+}
+
+/// Remove the given leaves from `tree`, as well as the inner nodes left empty;
+/// the inner nodes left with a single child are merged with it
+pub fn remove_leaves(tree: &mut NewickTree, leaves: &[usize]) {
+    for &leaf in leaves {
+        let mut parent = tree.parent(leaf);
+        tree.delete_node(leaf);
+        while let Some(p) = parent {
+            if !tree[p].children().is_empty() || tree.is_root(p) {
+                break;
+            }
+            parent = tree.parent(p);
+            tree.delete_node(p);
+        }
+    }
+
+    let unary = tree
+        .nodes()
+        .filter(|&n| tree[n].children().len() == 1)
+        .collect::<Vec<_>>();
+    for n in unary {
+        // Already merged into its own unary parent
+        if tree.get(n).is_err() {
+            continue;
+        }
+        // The child takes the place of its parent, keeping its position among
+        // its siblings and the cumulated length of both branches
+        while let [child] = *tree[n].children() {
+            for gc in tree[child].children().to_vec() {
+                tree.move_node(gc, n);
+            }
+            let data = std::mem::replace(
+                tree[child].data_mut(),
+                Data {
+                    name: None,
+                    attrs: Default::default(),
+                },
+            );
+            *tree[n].data_mut() = data;
+            if let Some(&b) = tree[child].branch() {
+                let length = tree[n].branch().copied().unwrap_or_default() + b;
+                tree[n].set_branch(length);
+            }
+            tree.delete_node(child);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn species_of(t: &NewickTree, name: &str) -> Option<String> {
+        let n = t.find_node(|n| n.name.as_deref() == Some(name)).unwrap();
+        t.attrs(n).get("S").cloned()
+    }
+
+    #[test]
+    fn species_pattern() {
+        let re = leaf_species_pattern("^(?P<species>[a-z]+)_").unwrap();
+        assert_eq!(&re.captures("hsapiens_12").unwrap()["species"], "hsapiens");
+        assert!(leaf_species_pattern("^([a-z]+)_").is_err());
+        assert!(leaf_species_pattern("^(?P<species>[a-z+_").is_err());
+    }
+
+    #[test]
+    fn infer() {
+        let species_tree =
+            newick::one_from_string("((hsapiens,mmusculus)Euarchontoglires,drerio)Vertebrata;")
+                .unwrap();
+        let pattern = leaf_species_pattern("^(?P<species>[a-z]+)_").unwrap();
+        let mut t = newick::one_from_string(
+            "((hsapiens_1,mmusculus_1)A,(drerio_1,X[&&NHX:S=drerio])B[&&NHX:S=custom])R;",
+        )
+        .unwrap();
+        assert!(lacks_species(&t));
+        infer_species(&mut t, None, Some(&pattern), Some(&species_tree));
+        assert!(!lacks_species(&t));
+        assert_eq!(species_of(&t, "hsapiens_1").as_deref(), Some("hsapiens"));
+        assert_eq!(species_of(&t, "X").as_deref(), Some("drerio"));
+        assert_eq!(species_of(&t, "A").as_deref(), Some("Euarchontoglires"));
+        // Existing annotations are left untouched
+        assert_eq!(species_of(&t, "B").as_deref(), Some("custom"));
+        assert_eq!(species_of(&t, "R").as_deref(), Some("Vertebrata"));
+    }
+
+    #[test]
+    fn infer_unknown() {
+        let species_tree =
+            newick::one_from_string("((hsapiens,mmusculus)Euarchontoglires,drerio)Vertebrata;")
+                .unwrap();
+        let pattern = leaf_species_pattern("^(?P<species>[a-z]+)_").unwrap();
+        let mut t =
+            newick::one_from_string("((hsapiens_1,mmusculus_1)A,(drerio_1,UNKNOWN)B)R;").unwrap();
+        infer_species(&mut t, None, Some(&pattern), Some(&species_tree));
+        assert_eq!(species_of(&t, "UNKNOWN"), None);
+        assert_eq!(species_of(&t, "A").as_deref(), Some("Euarchontoglires"));
+        // Inner nodes above a leaf of unknown species are left unannotated
+        assert_eq!(species_of(&t, "B"), None);
+        assert_eq!(species_of(&t, "R"), None);
+        assert!(leaf_species(&t, Some(&pattern)).is_none());
+    }
 }
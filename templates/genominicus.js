@@ -5,23 +5,31 @@ const GENE_SPACING = 5;
 
 MAIN_GENE = "?";
 
+// Attach the details of the actual gene, if any, as a tooltip
+function setTitle(rect, g) {
+    if (g.title) {
+        rect.element("title").words(g.title);
+    }
+    return rect;
+}
+
 function drawGenes(it, landscape) {
     MAIN_GENE = landscape.me.name;
     var xoffset = WINDOW - landscape.lefts.length;
     for (const [i, g] of landscape.lefts.entries()) {
-        it.rect(GENE_WIDTH, GENE_HEIGHT)
+        setTitle(it.rect(GENE_WIDTH, GENE_HEIGHT), g)
             .fill(g.color)
             .attr({class: "iam-" + g.name + " gene"})
             .move((i + xoffset)*(GENE_WIDTH + GENE_SPACING), 2)
         ;
     }
-    it.rect(GENE_WIDTH, GENE_HEIGHT)
+    setTitle(it.rect(GENE_WIDTH, GENE_HEIGHT), landscape.me)
         .fill(landscape.me.color)
         .attr({class: "main-gene"})
         .move((WINDOW)*(GENE_WIDTH + GENE_SPACING), 2)
     ;
     for (const [i, g] of landscape.rights.entries()) {
-        it.rect(GENE_WIDTH, GENE_HEIGHT)
+        setTitle(it.rect(GENE_WIDTH, GENE_HEIGHT), g)
             .fill(g.color)
             .attr({class: "iam-" + g.name + " gene"})
             .move((i + WINDOW + 1)*(GENE_WIDTH + GENE_SPACING), 2);
@@ -64,12 +72,73 @@ function drawCluster(it, cluster) {
     return x;
 }
 
+// Fill a link template with the values of a gene
+function makeUrl(template, it) {
+    const values = {gene: it.gene, species: it.species, chr: it.chr, family: it.ancestral};
+    return template.replace(/{(gene|species|chr|family)}/g, (_, k) => encodeURIComponent(values[k]));
+}
+
+// A link to `template` if any, a plain tag otherwise
+function makeTag(text, template, it) {
+    var tag = document.createElement(template ? "a" : "span");
+    tag.classList.add("nametag");
+    tag.style.color = it.color;
+    tag.appendChild(document.createTextNode(text));
+    if (template) {
+        tag.href = makeUrl(template, it);
+    }
+    return tag;
+}
+
+// Annotate the data tree with the parents, the species below each node, and
+// collect its leaves, as most nodes are not rendered yet
+var LEAVES = [];
+
+function prepare(it, parent) {
+    it.parent = parent;
+    it.speciesSet = new Set();
+    if (it.children.length == 0) {
+        if (it.species != "") {
+            it.speciesSet.add(it.species);
+        }
+        if (it.gene != "") {
+            LEAVES.push(it);
+        }
+    }
+    it.children.forEach((c) => {
+        prepare(c, it);
+        c.speciesSet.forEach((s) => it.speciesSet.add(s));
+    });
+}
+
+// Fetch `url` from the API and hand its JSON to `then`; failures are shown in
+// `container` in place of what was to be drawn
+function fetchJson(url, container, then) {
+    fetch(url)
+        .then((r) => r.ok ? r.json() : r.text().then((e) => { throw new Error(e || r.statusText); }))
+        .then(then)
+        .catch((e) => {
+            let error = document.createElement("span");
+            error.classList.add("fetch-error");
+            error.textContent = e.message;
+            container.appendChild(error);
+        });
+}
+
+function drawClustered(l, it) {
+    MAIN_GENE = it.repr.me.name;
+    let width = drawCluster(l, it.clustered);
+    l.size(width, GENE_HEIGHT + 4);
+}
+
 function insertAt(it, root, depth, borderColor="") {
     var div = document.createElement("div");
     div.classList.add("node")
     if (borderColor !== "") {
-        div.style.borderColor == borderColor
+        div.style.borderColor = borderColor
     }
+    div.it = it;
+    it.div = div;
 
     var content = document.createElement("div");
     var links = document.createElement("div");
@@ -79,35 +148,54 @@ function insertAt(it, root, depth, borderColor="") {
 
 
     if (it.gene != "") {
-        var geneName = document.createElement("a");
-        geneName.classList.add("nametag");
-        geneName.style.color = it.color;
-        geneName.appendChild(document.createTextNode(it.gene))
-        geneName.href = "https://www.ensembl.org/Multi/Search/Results?q="+it.gene+";site=ensembl"
-        links.appendChild(geneName);
-
-        var locus = document.createElement("a");
-        locus.classList.add("nametag");
-        locus.style.color = it.color;
-        locus.appendChild(document.createTextNode(it.species + "/" + it.chr))
-        locus.href = "https://www.genomicus.bio.ens.psl.eu/genomicus-104.02/cgi-bin/search.pl?query="+it.gene+"&view=default&nocache=$random"
-        links.appendChild(locus);
-    }
-    if (it.clustered) {
+        div.classList.add("leaf");
+        links.appendChild(makeTag(it.gene, LINKS.gene, it));
+        links.appendChild(makeTag(it.species + "/" + it.chr, LINKS.locus, it));
+    }
+    if (it.clustered || (API !== null && it.node !== undefined)) {
         div.classList.add("node-container")
         var cluster = document.createElement("div");
         cluster.classList.add("cluster");
         var l = SVG().addTo(cluster);
-        let width = drawCluster(l, it.clustered);
-        l.size(width, GENE_HEIGHT + 4);
         content.appendChild(cluster);
+        if (it.clustered) {
+            drawClustered(l, it);
+        } else {
+            // Served alignments are fetched once the node is rendered
+            fetchJson(API + "/cluster/" + it.node, cluster, (clustered) => {
+                if (clustered) {
+                    it.clustered = clustered;
+                    drawClustered(l, it);
+                    setupGenes(div);
+                }
+            });
+        }
     } else {
         var l = SVG().addTo(content).size(2*WINDOW*(GENE_WIDTH + GENE_SPACING) + GENE_WIDTH + 2*GENE_SPACING, GENE_HEIGHT + 4);
-        drawGenes(l, it.repr);
+        if (API !== null && it.gene != "") {
+            // Served landscapes are fetched once the leaf is rendered
+            fetchJson(API + "/landscape/" + encodeURIComponent(it.gene), content, (landscape) => {
+                it.repr = landscape;
+                drawGenes(l, landscape);
+                setupGenes(div);
+            });
+        } else {
+            drawGenes(l, it.repr);
+        }
     }
 
     if (it.children.length > 0) {
-        div.addEventListener("click", function(e) { this.classList.toggle("condensed"); e.stopPropagation(); });
+        // Children are only rendered once their parent is expanded
+        div.classList.add("condensed");
+        div.depth = depth;
+        div.addEventListener("click", function(e) {
+            if (this.classList.contains("condensed")) {
+                expand(this);
+            } else {
+                this.classList.add("condensed");
+            }
+            e.stopPropagation();
+        });
         div.style.cursor = "pointer";
         if (it.isDuplication) {
             let p = it.confidence;
@@ -115,36 +203,320 @@ function insertAt(it, root, depth, borderColor="") {
             div.classList.add("D");
             div.style.borderLeftWidth="10px";
             div.style.borderColor = color;
-            it.children.forEach((i) => insertAt(i, div, depth-1, borderColor=color));
-        } else {
-            it.children.forEach((i) => insertAt(i, div, depth-1));
+            div.childrenColor = color;
+            div.addEventListener("mouseover", (e) => { showMrca(div); e.stopPropagation(); });
+            div.addEventListener("mouseleave", () => showMrca(null));
         }
     }
 
-
+    div.classList.toggle("search-match", it.match === true);
+    div.classList.toggle("filtered-out", isFilteredOut(it));
+    div.classList.toggle("species-highlight", isSelected(it));
+    setupGenes(div);
     root.appendChild(div);
 }
 
-window.onload = () => {
-    let root = document.getElementById("root")
-    data.children.forEach((i) => insertAt(i, root, 10));
+function expand(div) {
+    if (!div.rendered) {
+        div.it.children.forEach((i) => insertAt(i, div, div.depth - 1, div.childrenColor || ""));
+        div.rendered = true;
+    }
+    div.classList.remove("condensed");
+}
 
-    Array.prototype.forEach.call(document.getElementsByClassName("gene"), function(el) {
+// Family highlighting on hover
+function setupGenes(root) {
+    root.querySelectorAll(".gene").forEach(function(el) {
+        if (el.closest("div.node") !== root) {
+            return;
+        }
         el.classList.forEach(className => {
             if (className.startsWith("iam-")) {
+                el.classList.toggle("pinned-gene", className === PINNED);
                 el.addEventListener('mouseenter', e => {
                     Array.prototype.forEach.call(
                         document.getElementsByClassName(className),
-                        (ee) => ee.classList.toggle("highlight-gene")
+                        (ee) => ee.classList.add("highlight-gene")
                     );
                 });
                 el.addEventListener('mouseleave', e => {
                     Array.prototype.forEach.call(
                         document.getElementsByClassName(className),
-                        (ee) => ee.classList.toggle("highlight-gene")
+                        (ee) => ee.classList.remove("highlight-gene")
                     );
                 });
+                el.addEventListener('click', e => {
+                    pin(className);
+                    e.stopPropagation();
+                });
             }
         })
     });
 }
+
+// Search
+var MATCHES = [];
+var CURRENT_MATCH = -1;
+
+function search(query) {
+    MATCHES.forEach((m) => {
+        m.match = false;
+        if (m.div) {
+            m.div.classList.remove("search-match");
+        }
+    });
+    MATCHES = [];
+    CURRENT_MATCH = -1;
+    query = query.trim().toLowerCase();
+    if (query !== "") {
+        MATCHES = LEAVES.filter((l) =>
+            l.gene.toLowerCase().includes(query)
+                || l.species.toLowerCase().includes(query)
+                || l.ancestral === query
+        );
+        MATCHES.forEach((m) => {
+            m.match = true;
+            if (m.div) {
+                m.div.classList.add("search-match");
+            }
+        });
+    }
+    document.getElementById("search-count").textContent = query === "" ? "" : MATCHES.length + " match(es)";
+    nextMatch();
+}
+
+function nextMatch() {
+    if (MATCHES.length == 0) {
+        return;
+    }
+    CURRENT_MATCH = (CURRENT_MATCH + 1) % MATCHES.length;
+    let match = MATCHES[CURRENT_MATCH];
+    // Render and unfold the ancestors of the match, from the top down
+    let ancestors = [];
+    for (let p = match.parent; p && p !== data; p = p.parent) {
+        ancestors.unshift(p);
+    }
+    ancestors.forEach((a) => expand(a.div));
+    match.div.scrollIntoView({block: "center"});
+}
+
+// Species filter
+var HIDDEN_SPECIES = new Set();
+
+function isFilteredOut(it) {
+    return it.speciesSet.size > 0 && Array.from(it.speciesSet).every((s) => HIDDEN_SPECIES.has(s));
+}
+
+function filterSpecies() {
+    HIDDEN_SPECIES = new Set(
+        Array.from(document.querySelectorAll("#species-list input:not(:checked)")).map((i) => i.value)
+    );
+    document.querySelectorAll("div.node").forEach((n) =>
+        n.classList.toggle("filtered-out", isFilteredOut(n.it))
+    );
+}
+
+function setupSpeciesFilter() {
+    let list = document.getElementById("species-list");
+    Array.from(data.speciesSet).sort().forEach((s) => {
+        let label = document.createElement("label");
+        let checkbox = document.createElement("input");
+        checkbox.type = "checkbox";
+        checkbox.value = s;
+        checkbox.checked = true;
+        checkbox.addEventListener("change", filterSpecies);
+        label.appendChild(checkbox);
+        label.appendChild(document.createTextNode(" " + s));
+        list.appendChild(label);
+    });
+}
+
+// Pinned family
+var PINNED = null;
+
+function pin(className) {
+    Array.prototype.forEach.call(
+        document.getElementsByClassName("pinned-gene"),
+        (e) => e.classList.remove("pinned-gene")
+    );
+    let pinned = document.getElementById("pinned");
+    pinned.replaceChildren();
+    PINNED = className === PINNED ? null : className;
+    if (PINNED !== null) {
+        // Copy the collection, as it is live
+        Array.from(document.getElementsByClassName(PINNED)).forEach((e) => e.classList.add("pinned-gene"));
+        pinned.appendChild(document.createTextNode("pinned: family " + PINNED.substring(4) + " "));
+        let clear = document.createElement("button");
+        clear.textContent = "×";
+        clear.addEventListener("click", () => pin(PINNED));
+        pinned.appendChild(clear);
+    }
+}
+
+// Species tree
+var SPECIES_NODES = [];
+var SELECTED_SPECIES = new Set();
+
+function drawSpeciesTree(root, node, present) {
+    let li = document.createElement("li");
+    let tag = document.createElement("span");
+    tag.classList.add("species-node");
+    tag.appendChild(document.createTextNode(node.name || "·"));
+    li.appendChild(tag);
+
+    let leaves = new Set();
+    if (node.children.length == 0) {
+        leaves.add(node.name);
+        if (!present.has(node.name)) {
+            tag.classList.add("absent");
+        }
+    } else {
+        let ul = document.createElement("ul");
+        node.children.forEach((c) => drawSpeciesTree(ul, c, present).forEach((s) => leaves.add(s)));
+        li.appendChild(ul);
+    }
+    tag.addEventListener("click", () => selectSpecies(tag, leaves));
+    SPECIES_NODES.push({tag: tag, leaves: leaves});
+    root.appendChild(li);
+    return leaves;
+}
+
+function isSelected(it) {
+    return Array.from(it.speciesSet).some((s) => SELECTED_SPECIES.has(s));
+}
+
+function selectSpecies(tag, leaves) {
+    let selected = !tag.classList.contains("selected");
+    document.querySelectorAll(".species-node.selected").forEach((t) => t.classList.remove("selected"));
+    SELECTED_SPECIES = selected ? leaves : new Set();
+    document.querySelectorAll("div.node").forEach((n) =>
+        n.classList.toggle("species-highlight", isSelected(n.it))
+    );
+    tag.classList.toggle("selected", selected);
+}
+
+// Highlight the MRCA of the species below a duplication node
+function showMrca(div) {
+    document.querySelectorAll(".species-node.mrca").forEach((t) => t.classList.remove("mrca"));
+    if (div === null) {
+        return;
+    }
+    let species = div.it.speciesSet;
+    if (species.size == 0) {
+        return;
+    }
+    // Nodes are stored in post-order, so the first match is the deepest one
+    let mrca = SPECIES_NODES.find((n) => Array.from(species).every((s) => n.leaves.has(s)));
+    if (mrca) {
+        mrca.tag.classList.add("mrca");
+    }
+}
+
+function setupSpeciesPanel() {
+    if (SPECIES_TREE === null) {
+        return;
+    }
+    let ul = document.createElement("ul");
+    drawSpeciesTree(ul, SPECIES_TREE, data.speciesSet);
+    document.getElementById("species-panel").appendChild(ul);
+}
+
+// SVG export of the currently visible state
+const EXPORT_ROW_HEIGHT = GENE_HEIGHT + 10;
+const EXPORT_INDENT = 15;
+const EXPORT_CHAR_WIDTH = 7;
+const EXPORT_STYLE = `
+    text { font-family: monospace; font-size: 12px; }
+    .gene { stroke: #323232; stroke-width: 0.5; }
+    .main-gene { stroke: #000; stroke-width: 4; }
+    .highlight-gene { stroke: #111; stroke-width: 2; }
+    .pinned-gene { stroke: #000; stroke-width: 3; }
+`;
+
+function escapeXml(s) {
+    return s.replace(/[<>&"]/g, (c) => ({"<": "&lt;", ">": "&gt;", "&": "&amp;", '"': "&quot;"})[c]);
+}
+
+function exportSvg() {
+    // Collect the visible rows, and the vertical extent of the expanded nodes
+    let rows = [];
+    let branches = [];
+    function walk(div, depth) {
+        if (div.classList.contains("filtered-out")) {
+            return;
+        }
+        let condensed = div.classList.contains("condensed") || div.it.children.length == 0;
+        // The landscapes of expanded clusters are hidden, as in the CSS
+        if (condensed || !div.classList.contains("node-container")) {
+            rows.push({div: div, depth: depth});
+        }
+        if (!condensed) {
+            let first = rows.length;
+            Array.from(div.children)
+                .filter((c) => c.classList.contains("node"))
+                .forEach((c) => walk(c, depth + 1));
+            if (rows.length > first) {
+                branches.push({depth: depth, first: first, last: rows.length - 1, color: div.childrenColor || "#aaa"});
+            }
+        }
+    }
+    Array.from(document.getElementById("root").children).forEach((c) => walk(c, 0));
+
+    let maxDepth = rows.reduce((ax, r) => Math.max(ax, r.depth), 0);
+    let labels = rows.map((r) => r.div.it.gene != "" ? r.div.it.gene + " " + r.div.it.species + "/" + r.div.it.chr : "");
+    let xlabels = (maxDepth + 1) * EXPORT_INDENT;
+    let xlandscapes = xlabels + labels.reduce((ax, l) => Math.max(ax, l.length), 0) * EXPORT_CHAR_WIDTH + 20;
+    let width = xlandscapes;
+    let y = (i) => i * EXPORT_ROW_HEIGHT;
+
+    let out = [];
+    for (const b of branches) {
+        let x = b.depth * EXPORT_INDENT + EXPORT_INDENT / 2;
+        out.push(`<line x1="${x}" y1="${y(b.first) + EXPORT_ROW_HEIGHT / 2}" x2="${x}" y2="${y(b.last) + EXPORT_ROW_HEIGHT / 2}" stroke="${b.color}" stroke-width="2"/>`);
+    }
+    rows.forEach((r, i) => {
+        let cy = y(i) + EXPORT_ROW_HEIGHT / 2;
+        if (r.div.classList.contains("search-match") || r.div.classList.contains("species-highlight")) {
+            out.push(`<rect x="0" y="${y(i)}" width="100%" height="${EXPORT_ROW_HEIGHT}" fill="${r.div.classList.contains("search-match") ? "#ffc800" : "#64c864"}" fill-opacity="0.4"/>`);
+        }
+        let x = Math.max(0, r.depth * EXPORT_INDENT - EXPORT_INDENT / 2);
+        out.push(`<line x1="${x}" y1="${cy}" x2="${xlabels - 5}" y2="${cy}" stroke="#aaa"/>`);
+        if (labels[i] != "") {
+            out.push(`<text x="${xlabels}" y="${cy + 4}" fill="${r.div.it.color}">${escapeXml(labels[i])}</text>`);
+        }
+        // Only the node's own landscape, not the ones of its descendants
+        let landscape = r.div.querySelector(":scope > .intrinsic svg");
+        if (landscape) {
+            let clone = landscape.cloneNode(true);
+            clone.setAttribute("x", xlandscapes);
+            clone.setAttribute("y", y(i) + (EXPORT_ROW_HEIGHT - GENE_HEIGHT - 4) / 2);
+            out.push(new XMLSerializer().serializeToString(clone));
+            width = Math.max(width, xlandscapes + parseFloat(landscape.getAttribute("width")));
+        }
+    });
+
+    let svg = `<svg xmlns="http://www.w3.org/2000/svg" width="${width}" height="${y(rows.length)}">`
+        + `<style>${EXPORT_STYLE}</style>`
+        + out.join("\n")
+        + "</svg>";
+    let url = URL.createObjectURL(new Blob([svg], {type: "image/svg+xml"}));
+    let a = document.createElement("a");
+    a.href = url;
+    a.download = document.title.replace(/\.html$/, "") + ".svg";
+    a.click();
+    // Some browsers start the download asynchronously
+    setTimeout(() => URL.revokeObjectURL(url), 0);
+}
+
+window.onload = () => {
+    prepare(data, null);
+    let root = document.getElementById("root")
+    data.children.forEach((i) => insertAt(i, root, 10));
+
+    let searchBox = document.getElementById("search");
+    searchBox.addEventListener("input", () => search(searchBox.value));
+    searchBox.addEventListener("keydown", (e) => { if (e.key === "Enter") { nextMatch(); } });
+    setupSpeciesFilter();
+    setupSpeciesPanel();
+    document.getElementById("export").addEventListener("click", exportSvg);
+}